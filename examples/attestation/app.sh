@@ -0,0 +1,10 @@
+#!/bin/sh
+
+# A placeholder service, the example is about the responder in attestation.sh
+if [ "$1" = respond ]; then
+    printf 'HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\r\n'
+    echo 'Run `nitrogen attest` to check what this enclave is running.'
+    exit
+fi
+
+socat tcp-listen:8080,bind=127.0.0.1,reuseaddr,fork exec:'/bin/sh /app.sh respond'
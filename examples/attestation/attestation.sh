@@ -0,0 +1,5 @@
+#!/bin/sh
+
+# Answer `nitrogen attest` on vsock port 5005, or NITROGEN_ATTESTATION_PORT
+# to match --attestation-port. Every connection runs the responder once.
+socat vsock-listen:"${NITROGEN_ATTESTATION_PORT:-5005}",reuseaddr,fork exec:/nsm-responder &
@@ -0,0 +1,50 @@
+//! Answers one `nitrogen attest` request: reads a hex encoded nonce line on
+//! stdin and writes the raw COSE_Sign1 attestation document the NSM device
+//! returns for it to stdout. `attestation.sh` runs it for every vsock
+//! connection through socat.
+
+use aws_nitro_enclaves_nsm_api::api::{Request, Response};
+use aws_nitro_enclaves_nsm_api::driver::{nsm_exit, nsm_init, nsm_process_request};
+use serde_bytes::ByteBuf;
+use std::io::{self, BufRead, Write};
+use std::process::exit;
+
+fn fail(message: &str) -> ! {
+    eprintln!("nsm-responder: {}", message);
+    exit(1)
+}
+
+fn main() {
+    let mut line = String::new();
+    if let Err(err) = io::stdin().lock().read_line(&mut line) {
+        fail(&format!("unable to read the nonce: {}", err));
+    }
+    let nonce = match hex::decode(line.trim()) {
+        Ok(nonce) => nonce,
+        Err(err) => fail(&format!("the nonce is not hex encoded: {}", err)),
+    };
+
+    let fd = nsm_init();
+    if fd < 0 {
+        fail("unable to open /dev/nsm");
+    }
+    let response = nsm_process_request(
+        fd,
+        Request::Attestation {
+            user_data: None,
+            nonce: Some(ByteBuf::from(nonce)),
+            public_key: None,
+        },
+    );
+    nsm_exit(fd);
+
+    match response {
+        Response::Attestation { document } => {
+            if let Err(err) = io::stdout().lock().write_all(&document) {
+                fail(&format!("unable to write the document: {}", err));
+            }
+        }
+        Response::Error(code) => fail(&format!("the NSM device answered {:?}", code)),
+        _ => fail("the NSM device answered with something other than a document"),
+    }
+}
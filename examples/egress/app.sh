@@ -0,0 +1,11 @@
+#!/bin/sh
+
+# Answer every request with the public IP the enclave's outbound traffic
+# leaves from, fetched through the vsock-proxy on the host
+if [ "$1" = respond ]; then
+    printf 'HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\r\n'
+    curl -s https://checkip.amazonaws.com
+    exit
+fi
+
+socat tcp-listen:8080,bind=127.0.0.1,reuseaddr,fork exec:'/bin/sh /app.sh respond'
@@ -0,0 +1,28 @@
+#!/bin/sh
+# Reusable helper giving an enclave outbound access to the destinations that
+# `nitrogen deploy --egress` allows. Source it from run.sh once the loopback
+# interface is up.
+#
+# NITROGEN_EGRESS lists host:port pairs separated by spaces, in the same order
+# as the --egress flags, e.g.
+#   NITROGEN_EGRESS="kms.us-east-1.amazonaws.com:443 checkip.amazonaws.com:443"
+#
+# Each destination gets its own loopback address, named after the host in
+# /etc/hosts, where socat tunnels connections to the host's vsock-proxy for it.
+# The proxies listen on vsock ports 8000, 8001, ... of the parent instance.
+
+PARENT_CID=3
+vsock_port=8000
+address=2
+
+for destination in $NITROGEN_EGRESS; do
+    host=${destination%:*}
+    port=${destination##*:}
+
+    ip addr add 127.0.0.$address/32 dev lo
+    echo "127.0.0.$address $host" >> /etc/hosts
+    socat tcp-listen:$port,bind=127.0.0.$address,reuseaddr,fork vsock-connect:$PARENT_CID:$vsock_port &
+
+    vsock_port=$((vsock_port + 1))
+    address=$((address + 1))
+done
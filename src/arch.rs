@@ -0,0 +1,50 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// CPU architecture of an instance and of the EIFs it can run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+pub enum Arch {
+    #[default]
+    #[serde(rename = "x86_64")]
+    #[value(name = "x86_64")]
+    X86_64,
+    /// AWS Graviton
+    #[serde(rename = "aarch64")]
+    #[value(name = "aarch64")]
+    Aarch64,
+}
+
+impl Arch {
+    /// Platform the enclave image and the eif-builder are run as.
+    pub fn docker_platform(&self) -> &'static str {
+        match self {
+            Arch::X86_64 => "linux/amd64",
+            Arch::Aarch64 => "linux/arm64",
+        }
+    }
+
+    /// SSM parameter holding the latest Amazon Linux 2 AMI.
+    pub fn ami_parameter(&self) -> &'static str {
+        match self {
+            Arch::X86_64 => "/aws/service/ami-amazon-linux-latest/amzn2-ami-hvm-x86_64-gp2",
+            Arch::Aarch64 => "/aws/service/ami-amazon-linux-latest/amzn2-ami-hvm-arm64-gp2",
+        }
+    }
+
+    pub fn default_instance_type(&self) -> &'static str {
+        match self {
+            Arch::X86_64 => "m5a.xlarge",
+            Arch::Aarch64 => "m6g.xlarge",
+        }
+    }
+}
+
+impl fmt::Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Arch::X86_64 => write!(f, "x86_64"),
+            Arch::Aarch64 => write!(f, "aarch64"),
+        }
+    }
+}
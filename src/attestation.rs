@@ -0,0 +1,487 @@
+use crate::enclave::Measurements;
+use failure::Error;
+use p384::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use serde_cbor::Value;
+use std::collections::BTreeMap;
+use x509_parser::{
+    certificate::X509Certificate, oid_registry::OID_SIG_ECDSA_WITH_SHA384, pem::parse_x509_pem,
+    prelude::FromDer, time::ASN1Time,
+};
+
+/// Root of trust for every Nitro Enclave attestation document.
+/// https://docs.aws.amazon.com/enclaves/latest/user/verify-root.html
+pub const AWS_NITRO_ROOT_G1: &str = include_str!("certs/aws-nitro-enclaves-root-g1.pem");
+
+/// CBOR tag of a COSE_Sign1 structure.
+const COSE_SIGN1_TAG: u64 = 18;
+/// COSE algorithm identifier for ECDSA w/ SHA-384.
+const COSE_ES384: i128 = -35;
+
+#[derive(Deserialize)]
+struct RawDocument {
+    module_id: String,
+    digest: String,
+    timestamp: u64,
+    pcrs: BTreeMap<u16, ByteBuf>,
+    certificate: ByteBuf,
+    cabundle: Vec<ByteBuf>,
+    public_key: Option<ByteBuf>,
+    user_data: Option<ByteBuf>,
+    nonce: Option<ByteBuf>,
+}
+
+/// Payload of an attestation document whose signature chain has been verified.
+#[derive(Clone, Debug, Serialize)]
+pub struct AttestationDocument {
+    pub module_id: String,
+    pub digest: String,
+    /// Milliseconds since the UNIX epoch at which the document was signed.
+    pub timestamp: u64,
+    /// Hex encoded PCR values, keyed by PCR index.
+    pub pcrs: BTreeMap<u16, String>,
+    pub public_key: Option<String>,
+    pub user_data: Option<String>,
+    pub nonce: Option<String>,
+}
+
+impl AttestationDocument {
+    pub fn pcr(&self, index: u16) -> Option<&str> {
+        self.pcrs.get(&index).map(String::as_str)
+    }
+}
+
+/// Outcome of comparing one PCR of a running enclave to the build measurement.
+#[derive(Clone, Debug, Serialize)]
+pub struct PcrCheck {
+    pub index: u16,
+    pub expected: String,
+    pub actual: Option<String>,
+    pub matches: bool,
+}
+
+/// Compare the PCRs of an attested enclave to the measurements of the EIF
+/// it is expected to be running.
+pub fn check_pcrs(document: &AttestationDocument, expected: &Measurements) -> Vec<PcrCheck> {
+    [
+        (0, Some(&expected.pcr0)),
+        (1, Some(&expected.pcr1)),
+        (2, Some(&expected.pcr2)),
+        (8, expected.pcr8.as_ref()),
+    ]
+    .into_iter()
+    // PCR8 is only meaningful for signed EIFs
+    .filter_map(|(index, expected)| Some((index, expected?)))
+    .map(|(index, expected)| {
+        let actual = document.pcr(index).map(str::to_string);
+        PcrCheck {
+            index,
+            matches: actual
+                .as_deref()
+                .is_some_and(|actual| actual.eq_ignore_ascii_case(expected)),
+            expected: expected.to_string(),
+            actual,
+        }
+    })
+    .collect()
+}
+
+/// Verify a COSE_Sign1 attestation document: its ES384 signature against the
+/// enclave certificate, the certificate chain up to the AWS Nitro root, and
+/// that it answers our `nonce`.
+pub fn verify(cose: &[u8], nonce: &[u8]) -> Result<AttestationDocument, Error> {
+    verify_rooted(cose, nonce, AWS_NITRO_ROOT_G1)
+}
+
+/// [`verify`], with the chain rooted in the PEM certificate `root`.
+fn verify_rooted(cose: &[u8], nonce: &[u8], root: &str) -> Result<AttestationDocument, Error> {
+    let CoseSign1 {
+        protected,
+        payload,
+        signature,
+    } = parse_cose_sign1(cose)?;
+    check_algorithm(&protected)?;
+
+    let raw: RawDocument = match serde_cbor::from_slice(&payload) {
+        Ok(raw) => raw,
+        Err(err) => {
+            return Err(failure::err_msg(format!(
+                "Malformed attestation document payload: {}",
+                err
+            )))
+        }
+    };
+    if raw.digest != "SHA384" {
+        return Err(failure::err_msg(format!(
+            "Unsupported attestation document digest {}.",
+            raw.digest
+        )));
+    }
+
+    let sig_structure = sig_structure(&protected, &payload)?;
+    let signature = match Signature::from_slice(&signature) {
+        Ok(signature) => signature,
+        Err(_) => {
+            return Err(failure::err_msg(
+                "Malformed attestation document signature.",
+            ))
+        }
+    };
+    let (_, leaf) = parse_der(&raw.certificate)?;
+    if public_key(&leaf)?
+        .verify(&sig_structure, &signature)
+        .is_err()
+    {
+        return Err(failure::err_msg(
+            "Attestation document signature does not match the enclave certificate.",
+        ));
+    }
+
+    verify_chain(&raw, &leaf, root)?;
+
+    match &raw.nonce {
+        Some(signed) if signed.as_slice() == nonce => {}
+        _ => return Err(failure::err_msg(
+            "Attestation document does not carry the requested nonce, it may have been replayed.",
+        )),
+    }
+
+    Ok(AttestationDocument {
+        module_id: raw.module_id,
+        digest: raw.digest,
+        timestamp: raw.timestamp,
+        pcrs: raw
+            .pcrs
+            .into_iter()
+            .map(|(index, value)| (index, hex::encode(value)))
+            .collect(),
+        public_key: raw.public_key.map(hex::encode),
+        user_data: raw.user_data.map(hex::encode),
+        nonce: raw.nonce.map(hex::encode),
+    })
+}
+
+/// The structure a COSE_Sign1 signature is computed over, as defined by
+/// RFC 8152 section 4.4, with no external data.
+fn sig_structure(protected: &[u8], payload: &[u8]) -> Result<Vec<u8>, Error> {
+    Ok(serde_cbor::to_vec(&Value::Array(vec![
+        Value::Text("Signature1".to_string()),
+        Value::Bytes(protected.to_vec()),
+        Value::Bytes(vec![]),
+        Value::Bytes(payload.to_vec()),
+    ]))?)
+}
+
+struct CoseSign1 {
+    protected: Vec<u8>,
+    payload: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+fn parse_cose_sign1(cose: &[u8]) -> Result<CoseSign1, Error> {
+    let value: Value = match serde_cbor::from_slice(cose) {
+        Ok(value) => value,
+        Err(err) => {
+            return Err(failure::err_msg(format!(
+                "Attestation document is not valid CBOR: {}",
+                err
+            )))
+        }
+    };
+    // The tag is optional when the context already implies COSE_Sign1
+    let value = match value {
+        Value::Tag(COSE_SIGN1_TAG, inner) => *inner,
+        other => other,
+    };
+    match value {
+        Value::Array(items) => match <[Value; 4]>::try_from(items) {
+            Ok(
+                [Value::Bytes(protected), Value::Map(_), Value::Bytes(payload), Value::Bytes(signature)],
+            ) => Ok(CoseSign1 {
+                protected,
+                payload,
+                signature,
+            }),
+            _ => Err(failure::err_msg(
+                "Attestation document is not a COSE_Sign1 structure.",
+            )),
+        },
+        _ => Err(failure::err_msg(
+            "Attestation document is not a COSE_Sign1 structure.",
+        )),
+    }
+}
+
+fn check_algorithm(protected: &[u8]) -> Result<(), Error> {
+    let headers: BTreeMap<i128, Value> = serde_cbor::from_slice(protected)?;
+    match headers.get(&1) {
+        Some(Value::Integer(COSE_ES384)) => Ok(()),
+        other => Err(failure::err_msg(format!(
+            "Unsupported attestation document signature algorithm {:?}.",
+            other
+        ))),
+    }
+}
+
+/// `cabundle` runs from the root to the issuer of the enclave certificate.
+fn verify_chain(raw: &RawDocument, leaf: &X509Certificate, root: &str) -> Result<(), Error> {
+    let root = match parse_x509_pem(root.as_bytes()) {
+        Ok((_, pem)) => pem.contents,
+        Err(_) => {
+            return Err(failure::err_msg(
+                "Unable to parse the AWS Nitro root certificate.",
+            ))
+        }
+    };
+    match raw.cabundle.first() {
+        Some(bundled_root) if bundled_root.as_slice() == root.as_slice() => {}
+        _ => {
+            return Err(failure::err_msg(
+                "Attestation document is not rooted in the AWS Nitro Enclaves root certificate.",
+            ))
+        }
+    }
+
+    let signed_at = ASN1Time::from_timestamp((raw.timestamp / 1000) as i64)?;
+    let mut chain = vec![];
+    for der in &raw.cabundle {
+        chain.push(parse_der(der)?.1);
+    }
+    chain.push(leaf.clone());
+
+    for (position, cert) in chain.iter().enumerate() {
+        if !cert.validity().is_valid_at(signed_at) {
+            return Err(failure::err_msg(format!(
+                "Certificate '{}' was not valid when the attestation document was signed.",
+                cert.subject()
+            )));
+        }
+        // The root is trusted by identity, everything below must be signed by its parent
+        let issuer = match position {
+            0 => cert,
+            _ => &chain[position - 1],
+        };
+        verify_signed_by(cert, issuer)?;
+    }
+    Ok(())
+}
+
+fn verify_signed_by(cert: &X509Certificate, issuer: &X509Certificate) -> Result<(), Error> {
+    if cert.signature_algorithm.algorithm != OID_SIG_ECDSA_WITH_SHA384 {
+        return Err(failure::err_msg(format!(
+            "Certificate '{}' is not signed with ecdsa-with-SHA384.",
+            cert.subject()
+        )));
+    }
+    let signature = match Signature::from_der(&cert.signature_value.data) {
+        Ok(signature) => signature,
+        Err(_) => {
+            return Err(failure::err_msg(format!(
+                "Certificate '{}' has a malformed signature.",
+                cert.subject()
+            )))
+        }
+    };
+    match public_key(issuer)?.verify(cert.tbs_certificate.as_ref(), &signature) {
+        Ok(()) => Ok(()),
+        Err(_) => Err(failure::err_msg(format!(
+            "Certificate '{}' is not signed by '{}'.",
+            cert.subject(),
+            issuer.subject()
+        ))),
+    }
+}
+
+fn parse_der(der: &[u8]) -> Result<(&[u8], X509Certificate<'_>), Error> {
+    match X509Certificate::from_der(der) {
+        Ok(parsed) => Ok(parsed),
+        Err(err) => Err(failure::err_msg(format!(
+            "Malformed certificate in attestation document: {}",
+            err
+        ))),
+    }
+}
+
+fn public_key(cert: &X509Certificate) -> Result<VerifyingKey, Error> {
+    match VerifyingKey::from_sec1_bytes(&cert.public_key().subject_public_key.data) {
+        Ok(key) => Ok(key),
+        Err(_) => Err(failure::err_msg(format!(
+            "Certificate '{}' does not hold a P-384 public key.",
+            cert.subject()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p384::ecdsa::{signature::Signer, SigningKey};
+
+    /// A root, intermediate and enclave certificate shaped like the AWS chain,
+    /// all P-384 with ecdsa-with-SHA384, valid from 2026-10-18 for 100 years.
+    const ROOT: &str = include_str!("certs/testdata/root.pem");
+    const INTERMEDIATE: &str = include_str!("certs/testdata/intermediate.pem");
+    const ENCLAVE: &str = include_str!("certs/testdata/enclave.pem");
+    /// Private key of `ENCLAVE`, which signs the documents.
+    const ENCLAVE_KEY: &str =
+        "222cee677c31a8bba20677224d9154c00f383bafa9e8b82b502f81cbfa71e8dc44370b41405dc6d5ffe0a039b3ce0abc";
+    /// 2030-01-01, in milliseconds.
+    const TIMESTAMP: u64 = 1_893_456_000_000;
+    const NONCE: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn der(pem: &str) -> Vec<u8> {
+        parse_x509_pem(pem.as_bytes()).unwrap().1.contents
+    }
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.to_string())
+    }
+
+    fn payload(timestamp: u64, cabundle: &[&str]) -> Vec<u8> {
+        let pcrs = (0..3)
+            .map(|index| (Value::Integer(index), Value::Bytes(vec![index as u8; 48])))
+            .collect();
+        let fields = [
+            ("module_id", text("i-0123456789abcdef0-enc0123456789abcdef")),
+            ("digest", text("SHA384")),
+            ("timestamp", Value::Integer(timestamp.into())),
+            ("pcrs", Value::Map(pcrs)),
+            ("certificate", Value::Bytes(der(ENCLAVE))),
+            (
+                "cabundle",
+                Value::Array(cabundle.iter().map(|pem| Value::Bytes(der(pem))).collect()),
+            ),
+            ("public_key", Value::Null),
+            ("user_data", Value::Null),
+            ("nonce", Value::Bytes(NONCE.to_vec())),
+        ];
+        let fields = fields
+            .into_iter()
+            .map(|(key, value)| (text(key), value))
+            .collect();
+        serde_cbor::to_vec(&Value::Map(fields)).unwrap()
+    }
+
+    /// A COSE_Sign1 document over `payload`, signed by the enclave key.
+    fn sign(payload: Vec<u8>, algorithm: i128) -> Vec<u8> {
+        let protected = serde_cbor::to_vec(&Value::Map(
+            [(Value::Integer(1), Value::Integer(algorithm))].into(),
+        ))
+        .unwrap();
+        let key = SigningKey::from_slice(&hex::decode(ENCLAVE_KEY).unwrap()).unwrap();
+        let signature: Signature = key.sign(&sig_structure(&protected, &payload).unwrap());
+        serde_cbor::to_vec(&Value::Array(vec![
+            Value::Bytes(protected),
+            Value::Map(BTreeMap::new()),
+            Value::Bytes(payload),
+            Value::Bytes(signature.to_bytes().to_vec()),
+        ]))
+        .unwrap()
+    }
+
+    fn document() -> Vec<u8> {
+        sign(payload(TIMESTAMP, &[ROOT, INTERMEDIATE]), COSE_ES384)
+    }
+
+    fn error(result: Result<AttestationDocument, Error>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    #[test]
+    fn sig_structure_is_rfc8152_signature1() {
+        let expected = [
+            &[0x84, 0x6a][..],
+            b"Signature1",
+            &[0x43, 0xa1, 0x01, 0x38],
+            &[0x40],
+            &[0x42, 0xa0, 0xff],
+        ]
+        .concat();
+        assert_eq!(
+            sig_structure(&[0xa1, 0x01, 0x38], &[0xa0, 0xff]).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn verifies_a_chained_document() {
+        let document = verify_rooted(&document(), NONCE, ROOT).unwrap();
+        assert_eq!(document.timestamp, TIMESTAMP);
+        assert_eq!(document.pcr(1), Some(&*"01".repeat(48)));
+        assert_eq!(document.pcr(8), None);
+        assert_eq!(document.nonce, Some(hex::encode(NONCE)));
+    }
+
+    #[test]
+    fn accepts_tagged_cose_sign1() {
+        // 0xd2 is CBOR tag 18
+        let tagged = [&[0xd2][..], &document()].concat();
+        verify_rooted(&tagged, NONCE, ROOT).unwrap();
+    }
+
+    #[test]
+    fn rejects_tampered_payload() {
+        let mut cose = document();
+        // Flip a bit in the middle of the payload
+        let at = cose.len() / 2;
+        cose[at] ^= 0x01;
+        assert!(verify_rooted(&cose, NONCE, ROOT).is_err());
+
+        // A payload signed for a different timestamp
+        let signed = sign(payload(TIMESTAMP, &[ROOT, INTERMEDIATE]), COSE_ES384);
+        let mut cose: Vec<Value> = serde_cbor::from_slice(&signed).unwrap();
+        cose[2] = Value::Bytes(payload(TIMESTAMP + 1, &[ROOT, INTERMEDIATE]));
+        let cose = serde_cbor::to_vec(&cose).unwrap();
+        assert!(error(verify_rooted(&cose, NONCE, ROOT)).contains("signature does not match"));
+    }
+
+    #[test]
+    fn rejects_other_algorithms() {
+        let cose = sign(payload(TIMESTAMP, &[ROOT, INTERMEDIATE]), -7);
+        assert!(error(verify_rooted(&cose, NONCE, ROOT)).contains("algorithm"));
+    }
+
+    #[test]
+    fn rejects_a_foreign_root() {
+        assert!(error(verify(&document(), NONCE)).contains("not rooted"));
+    }
+
+    #[test]
+    fn rejects_a_broken_chain() {
+        // The enclave certificate is issued by the intermediate, not the root
+        let cose = sign(payload(TIMESTAMP, &[ROOT]), COSE_ES384);
+        assert!(error(verify_rooted(&cose, NONCE, ROOT)).contains("is not signed by"));
+    }
+
+    #[test]
+    fn rejects_documents_signed_outside_validity() {
+        // 2020-01-01, before the certificates were issued
+        let cose = sign(
+            payload(1_577_836_800_000, &[ROOT, INTERMEDIATE]),
+            COSE_ES384,
+        );
+        assert!(error(verify_rooted(&cose, NONCE, ROOT)).contains("was not valid"));
+    }
+
+    #[test]
+    fn rejects_other_nonces() {
+        assert!(error(verify_rooted(&document(), b"replayed", ROOT)).contains("nonce"));
+    }
+
+    #[test]
+    fn checks_pcrs_against_measurements() {
+        let document = verify_rooted(&document(), NONCE, ROOT).unwrap();
+        let measurements = Measurements {
+            hash_algorithm: None,
+            pcr0: "00".repeat(48),
+            pcr1: "01".repeat(48).to_uppercase(),
+            pcr2: "ff".repeat(48),
+            pcr8: Some("08".repeat(48)),
+        };
+        let checks = check_pcrs(&document, &measurements);
+        let matches: Vec<_> = checks.iter().map(|pcr| (pcr.index, pcr.matches)).collect();
+        assert_eq!(matches, [(0, true), (1, true), (2, false), (8, false)]);
+        assert_eq!(checks[3].actual, None);
+    }
+}
@@ -1,17 +1,35 @@
 use rand::{distributions::Alphanumeric, Rng};
 use std::env::temp_dir;
-use std::fs::{create_dir, File};
+use std::fs::{self, create_dir, File};
 use std::io;
 use std::io::Write;
 use std::path::Path;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use aws_sdk_cloudformation::Client;
+use aws_sdk_cloudformation::{Client, Region};
 use clap::{Parser, Subcommand};
 use failure::Error;
-use nitrogen::commands::{build, delete, deploy, logs, setup};
+use nitrogen::arch::Arch;
+use nitrogen::caller_ip;
+use nitrogen::cf_utilities::{self, StackWaiter, DEFAULT_WAIT_TIMEOUT};
+use nitrogen::commands::attest::DEFAULT_ATTESTATION_PORT;
+use nitrogen::commands::build::Signing;
+use nitrogen::commands::delete::delete_dry_run;
+use nitrogen::commands::setup::{setup_dry_run, Network, OnFailure};
+use nitrogen::commands::{
+    attest, build, delete, deploy, inspect, list, logs, pin_host_key, setup, ssh_allow, update,
+    verify_build,
+};
+use nitrogen::egress::Destination;
+use nitrogen::ingress::Source;
+use nitrogen::manifest::BuildManifest;
+use nitrogen::output::{ListOutput, OutputFormat, StartOutput};
+use nitrogen::overlay;
+use nitrogen::ports::PortMapping;
+use nitrogen::sizing::DEFAULT_HEADROOM_MIB;
+use nitrogen::state::StackState;
 use nitrogen::template::SETUP_TEMPLATE;
-use tracing::{debug, info};
+use tracing::{debug, error, info, warn};
 
 use rust_embed::{EmbeddedFile, RustEmbed};
 
@@ -27,6 +45,14 @@ struct Cli {
 
     #[arg(short, long)]
     verbose: bool,
+
+    /// Format of the result document written to stdout
+    #[arg(short, long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// Seconds to wait for a CloudFormation stack operation or change set before giving up on it
+    #[arg(long, global = true, default_value_t = DEFAULT_WAIT_TIMEOUT.as_secs())]
+    wait_timeout: u64,
 }
 
 #[derive(Subcommand)]
@@ -37,18 +63,49 @@ enum Commands {
         name: String,
         /// Filepath of SSH public key to be used as EC2 instance key pair
         public_key: String,
-        /// EC2 instance type. Must be Nitro Enclaves compatible
-        #[arg(long, default_value_t = String::from("m5a.xlarge"))]
-        instance_type: String,
+        /// EC2 instance type. Must be Nitro Enclaves compatible. Defaults to m5a.xlarge, or m6g.xlarge for aarch64
+        #[arg(long)]
+        instance_type: Option<String>,
+        /// CPU architecture of the instance
+        #[arg(long, value_enum, default_value_t = Arch::X86_64)]
+        arch: Arch,
         /// EC2 root disk size GiBs
         #[arg(short, long, default_value_t = 8)]
         disk_size: usize,
-        /// EC2 instance port for socat enclave connection
-        #[arg(short, long, default_value_t = 5000)]
-        port: usize,
-        /// Source CIDR range for inbound SSH whitelist on the EC2 instance
-        #[arg(short, long, default_value_t = String::from("0.0.0.0/0"))]
-        ssh_location: String,
+        /// Instance port forwarded by socat to an enclave vsock port, as host[:vsock].
+        /// Repeat for several ports. The vsock port defaults to 5000
+        #[arg(short, long = "port", default_value = "5000")]
+        ports: Vec<PortMapping>,
+        /// Source allowed to reach the forwarded ports: an IPv4 or IPv6 CIDR range or a
+        /// security group id. Repeat for several sources
+        #[arg(long = "service-location", default_value = "0.0.0.0/0")]
+        service_locations: Vec<Source>,
+        /// Source CIDR range for inbound SSH whitelist on the EC2 instance. Defaults to
+        /// this machine's public IP
+        #[arg(short, long)]
+        ssh_location: Option<String>,
+        /// Existing VPC to launch the instance in, instead of the default VPC
+        #[arg(long, requires = "subnet_id")]
+        vpc_id: Option<String>,
+        /// Subnet of the VPC to launch the instance in
+        #[arg(long, requires = "vpc_id")]
+        subnet_id: Option<String>,
+        /// Launch without a public IP address. Later commands reach the instance at
+        /// its private IP, so they must run with a route into the VPC
+        #[arg(long, requires = "subnet_id", default_value_t = false)]
+        private: bool,
+        /// JSON or YAML overlay merged into the built-in CloudFormation template before submission
+        #[arg(long)]
+        template: Option<String>,
+        /// What CloudFormation does with the stack if creating it fails
+        #[arg(long, value_enum, default_value_t = OnFailure::Rollback)]
+        on_failure: OnFailure,
+        /// Delete a stack with this name left over from a failed setup without asking
+        #[arg(long, default_value_t = false)]
+        delete_failed: bool,
+        /// Validate the stack and show what would be created and its cost, without creating it
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
     },
 
     /// Build a enclave image file (EIF) from a given Dockerfile
@@ -63,6 +120,42 @@ enum Commands {
         /// Output EIF filepath
         #[arg(short, long, default_value_t = String::from("nitrogen.eif"))]
         eif: String,
+
+        /// Filepath of a PEM certificate to sign the EIF with, recorded as PCR8
+        #[arg(long, requires = "private_key")]
+        signing_certificate: Option<String>,
+
+        /// Filepath of the PEM private key of the signing certificate
+        #[arg(long, requires = "signing_certificate")]
+        private_key: Option<String>,
+
+        /// CPU architecture of the instance the EIF will run on
+        #[arg(long, value_enum, default_value_t = Arch::X86_64)]
+        arch: Arch,
+    },
+
+    /// Rebuild an EIF from its build manifest and check the measurements are unchanged
+    VerifyBuild {
+        /// Filepath of the EIF whose manifest to verify
+        #[arg(short, long, default_value_t = String::from("nitrogen.eif"))]
+        eif: String,
+
+        /// Filepath of the private key of the certificate the EIF was signed with
+        #[arg(long)]
+        private_key: Option<String>,
+    },
+
+    /// Show the sections, metadata and measurements of an EIF
+    Inspect {
+        /// Filepath of EIF
+        #[arg(default_value_t = String::from("nitrogen.eif"))]
+        eif: String,
+        /// Number of enclave CPUs to suggest an instance type for
+        #[arg(short, long, default_value_t = 2)]
+        cpu_count: u64,
+        /// Memory in MiB added to what the EIF needs
+        #[arg(long, default_value_t = DEFAULT_HEADROOM_MIB)]
+        memory_headroom: u64,
     },
 
     /// Deploy an EIF to a provisioned EC2 instance
@@ -72,31 +165,109 @@ enum Commands {
         /// Filepath of EIF
         #[arg(short, long, default_value_t = String::from("nitrogen.eif"))]
         eif: String,
-        /// Filepath of SSH private key of the EC2 instance
-        ssh_key: String,
-        /// Number of CPUs to provision for the enclave
-        #[arg(short, long, default_value_t = 2)]
-        cpu_count: u64,
-        /// Memory in MB to provision for the enclave. Defaults to 5x EIF size if not supplied.
+        /// Filepath of SSH private key of the EC2 instance. Defaults to the key last used with the stack
+        ssh_key: Option<String>,
+        /// Number of CPUs to provision for the enclave. Defaults to the last deployment's, or 2
+        #[arg(short, long)]
+        cpu_count: Option<u64>,
+        /// Memory in MiB to provision for the enclave. Defaults to what the EIF needs plus the headroom
         #[arg(short, long)]
         memory: Option<u64>,
+        /// Memory in MiB added to what the EIF needs when --memory is not given
+        #[arg(long, default_value_t = DEFAULT_HEADROOM_MIB)]
+        memory_headroom: u64,
         /// Debug mode
         #[arg(long, default_value_t = false)]
         debug_mode: bool,
+        /// Destination the enclave may connect out to through a vsock-proxy on the host,
+        /// as host:port. Repeat for several; see examples/egress. Defaults to the
+        /// destinations of the last deployment
+        #[arg(long)]
+        egress: Vec<Destination>,
+        /// Stop all egress proxies instead of reusing the last deployment's
+        #[arg(long, conflicts_with = "egress")]
+        no_egress: bool,
     },
 
     /// Get the logs from an enclave in debug mode.
     Logs {
         /// Name of a Nitrogen-generated CloudFormation stack
         name: String,
-        /// Filepath of SSH private key of the EC2 instance
-        ssh_key: String,
+        /// Filepath of SSH private key of the EC2 instance. Defaults to the key last used with the stack
+        ssh_key: Option<String>,
+    },
+
+    /// Verify the attestation document of a running enclave against the EIF's build measurements
+    Attest {
+        /// Name of a Nitrogen-generated CloudFormation stack
+        name: String,
+        /// Filepath of SSH private key of the EC2 instance. Defaults to the key last used with the stack
+        ssh_key: Option<String>,
+        /// Filepath of the EIF the enclave should be running. Defaults to the last deployed EIF
+        #[arg(short, long)]
+        eif: Option<String>,
+        /// Vsock port of the attestation responder inside the enclave
+        #[arg(long, default_value_t = DEFAULT_ATTESTATION_PORT)]
+        attestation_port: u32,
+    },
+
+    /// Change the parameters of a provisioned stack in place
+    Update {
+        /// Name of a Nitrogen-generated CloudFormation stack
+        name: String,
+        /// New EC2 instance type. Must be Nitro Enclaves compatible and of the same architecture
+        #[arg(long)]
+        instance_type: Option<String>,
+        /// New EC2 root disk size in GiBs
+        #[arg(short, long)]
+        disk_size: Option<usize>,
+        /// New host port of the first port mapping
+        #[arg(short, long)]
+        port: Option<u16>,
+        /// New source CIDR range for inbound SSH whitelist on the EC2 instance
+        #[arg(short, long)]
+        ssh_location: Option<String>,
+        /// New sources allowed to reach the forwarded ports, replacing the current ones.
+        /// Repeat for several sources
+        #[arg(long = "service-location")]
+        service_locations: Vec<Source>,
+        /// Go ahead even if resources, possibly the instance, have to be replaced
+        #[arg(long, default_value_t = false)]
+        allow_replacement: bool,
+    },
+
+    /// Change the CIDR range allowed to SSH into an instance
+    SshAllow {
+        /// Name of a Nitrogen-generated CloudFormation stack
+        name: String,
+        /// Source CIDR range to allow. Defaults to this machine's public IP
+        cidr: Option<String>,
     },
 
+    /// Pin the SSH host key of an instance whose stack does not publish one, as for
+    /// stacks created before host key pinning
+    PinHostKey {
+        /// Name of a Nitrogen-generated CloudFormation stack
+        name: String,
+        /// The instance's public host key, `<type> <base64>`, or a file holding it
+        host_key: String,
+    },
     /// Delete launched EC2 instance
     Delete {
         /// Name of the CloudFormation stack to delete
         name: String,
+        /// Show the resources that would be removed, without deleting them
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+
+    /// List the Nitrogen-managed stacks in the current region
+    List,
+
+    /// Show the locally recorded state of a stack
+    Show {
+        /// Name of a Nitrogen-generated CloudFormation stack
+        name: String,
     },
 
     /// All in one setup, build, and deploy
@@ -105,20 +276,37 @@ enum Commands {
         service: String,
         /// Filepath of SSH public key to be used as EC2 instance key pair
         public_key: String,
-        /// Filepath of SSH private key to be used for scp/ssh when deploying EIF
+        /// Filepath of SSH private key to be used for SSH/SFTP when deploying EIF
         private_key: String,
-        /// EC2 instance type. Must be Nitro Enclaves compatible
-        #[arg(long, default_value_t = String::from("m5a.xlarge"))]
-        instance_type: String,
+        /// EC2 instance type. Must be Nitro Enclaves compatible. Defaults to m5a.xlarge, or m6g.xlarge for aarch64
+        #[arg(long)]
+        instance_type: Option<String>,
+        /// CPU architecture of the instance and EIF
+        #[arg(long, value_enum, default_value_t = Arch::X86_64)]
+        arch: Arch,
         /// EC2 root disk size in GiBs
         #[arg(short, long, default_value_t = 8)]
         disk_size: usize,
-        /// EC2 instance port for socat enclave connection
-        #[arg(short, long, default_value_t = 5000)]
-        port: usize,
-        /// Source CIDR range for inbound SSH whitelist on the EC2 instance
-        #[arg(short, long, default_value_t = String::from("0.0.0.0/0"))]
-        ssh_location: String,
+        /// Instance port forwarded by socat to an enclave vsock port, as host[:vsock].
+        /// Repeat for several ports. The vsock port defaults to 5000
+        #[arg(short, long = "port", default_value = "5000")]
+        ports: Vec<PortMapping>,
+        /// Source allowed to reach the forwarded ports: an IPv4 or IPv6 CIDR range or a
+        /// security group id. Repeat for several sources
+        #[arg(long = "service-location", default_value = "0.0.0.0/0")]
+        service_locations: Vec<Source>,
+        /// Source CIDR range for inbound SSH whitelist on the EC2 instance. Defaults to
+        /// this machine's public IP
+        #[arg(short, long)]
+        ssh_location: Option<String>,
+        /// What happens to the stack if setup, build or deploy fails. DELETE tears
+        /// down everything start created
+        #[arg(long, value_enum, default_value_t = OnFailure::Delete)]
+        on_failure: OnFailure,
+        /// Destination the enclave may connect out to through a vsock-proxy on the host,
+        /// as host:port. Repeat for several; see examples/egress
+        #[arg(long)]
+        egress: Vec<Destination>,
     },
 }
 
@@ -135,21 +323,62 @@ async fn main() -> Result<(), Error> {
         .with_writer(io::stderr)
         .with_env_filter(tracing_directive)
         .init();
+    cf_utilities::exit_on_interrupt();
+    let waiter = StackWaiter::with_timeout(Duration::from_secs(cli.wait_timeout));
 
     match cli.command {
         Commands::Setup {
             name,
             instance_type,
+            arch,
             disk_size,
-            port,
+            ports,
+            service_locations,
             public_key,
             ssh_location,
+            vpc_id,
+            subnet_id,
+            private,
+            template,
+            on_failure,
+            delete_failed,
+            dry_run,
         } => {
-            let ssh_location = ssh_location.to_string();
-            let instance_type = instance_type.to_string();
-            let setup_template = SETUP_TEMPLATE.to_string();
-            let shared_config = aws_config::from_env().load().await;
-            let client = Client::new(&shared_config);
+            let ssh_location = caller_ip::ssh_location(ssh_location).await?;
+            let network = Network {
+                vpc_id,
+                subnet_id,
+                private,
+            };
+            let instance_type =
+                instance_type.unwrap_or_else(|| arch.default_instance_type().to_string());
+            let setup_template = match template {
+                Some(overlay) => {
+                    info!("Merging template overlay {}.", overlay);
+                    overlay::apply(SETUP_TEMPLATE, Path::new(&overlay))?
+                }
+                None => SETUP_TEMPLATE.to_string(),
+            };
+            let (client, region) = cloudformation_client(None).await;
+
+            if dry_run {
+                let plan = setup_dry_run(
+                    &client,
+                    &setup_template,
+                    &name,
+                    &instance_type,
+                    &disk_size,
+                    &ports,
+                    &service_locations,
+                    &public_key,
+                    &ssh_location,
+                    arch,
+                    &network,
+                    &waiter,
+                )
+                .await?;
+                return cli.output.print(&plan);
+            }
 
             info!("Spinning up enclave instance '{}'.", name);
             let outputs = setup(
@@ -158,81 +387,266 @@ async fn main() -> Result<(), Error> {
                 &name,
                 &instance_type,
                 &disk_size,
-                &port,
+                &ports,
+                &service_locations,
                 &public_key,
                 &ssh_location,
+                arch,
+                &network,
+                on_failure,
+                delete_failed,
+                &waiter,
             )
             .await?;
 
-            info!("Open ports: {}, {}", "22", port,);
+            info!("Open ports: {:?}", outputs.open_ports);
             info!(
                 name,
-                instance_id = outputs[0].1,
-                public_ip = outputs[1].1,
-                availability_zone = outputs[2].1,
-                public_dns = outputs[3].1,
+                instance_id = outputs.outputs.instance_id,
+                public_ip = outputs.outputs.public_ip,
+                availability_zone = outputs.outputs.availability_zone,
+                public_dns = outputs.outputs.public_dns,
+                private_ip = outputs.outputs.private_ip,
                 "User enclave information:"
             );
-            // TODO return outputs as a JSON struct
-            Ok(())
+            let state = StackState::new(
+                &outputs,
+                region,
+                &instance_type,
+                &ports,
+                &service_locations,
+                &ssh_location,
+                &public_key,
+            );
+            info!(state = %state.save()?.display(), "Recorded stack state.");
+            cli.output.emit(&outputs)
         }
         Commands::Build {
             dockerfile_dir,
             dockerfile_name,
             eif,
+            signing_certificate,
+            private_key,
+            arch,
         } => {
             info!(
                 dockerfile_dir,
                 dockerfile_name, "Building EIF from dockerfile."
             );
-            let out = build(&dockerfile_dir, &dockerfile_name, &eif).await?;
-            debug!(docker_output=?out, "Docker output:");
-            Ok(())
+            let signing = match (signing_certificate, private_key) {
+                (Some(certificate), Some(private_key)) => Some(Signing {
+                    certificate,
+                    private_key,
+                }),
+                _ => None,
+            };
+            let out = build(
+                &dockerfile_dir,
+                &dockerfile_name,
+                &eif,
+                signing.as_ref(),
+                arch,
+            )
+            .await?;
+            cli.output.emit(&out)
+        }
+        Commands::VerifyBuild { eif, private_key } => {
+            info!(eif, "Verifying EIF build is reproducible.");
+            let out = verify_build(&eif, private_key.as_deref()).await?;
+            cli.output.emit(&out)?;
+            if out.reproducible {
+                info!("Rebuilt EIF matches the build manifest.");
+                Ok(())
+            } else {
+                Err(failure::err_msg(
+                    "Rebuilt EIF does not match the build manifest.",
+                ))
+            }
         }
+        Commands::Inspect {
+            eif,
+            cpu_count,
+            memory_headroom,
+        } => cli
+            .output
+            .print(&inspect(&eif, cpu_count, memory_headroom)?),
         Commands::Deploy {
             name,
             eif,
             ssh_key,
             cpu_count,
             memory,
+            memory_headroom,
             debug_mode,
+            egress,
+            no_egress,
         } => {
+            let mut state = StackState::load(&name)?;
+            let ssh_key = resolve_ssh_key(&name, ssh_key, &state)?;
+            let previous = state.as_ref().and_then(|state| state.deployment.as_ref());
+            let cpu_count = cpu_count.or_else(|| Some(previous?.cpu_count)).unwrap_or(2);
+            let egress = match (egress.is_empty() && !no_egress, previous) {
+                (true, Some(previous)) => previous.egress.clone(),
+                _ => egress,
+            };
+
             info!(eif, "Deploying EIF to {}", name);
-            let shared_config = aws_config::from_env().load().await;
-            let client = Client::new(&shared_config);
+            let (client, _) = cloudformation_client(stack_region(&state)).await;
             let out = deploy(
-                &client, &name, &eif, &ssh_key, cpu_count, memory, debug_mode,
+                &client,
+                &name,
+                &eif,
+                &ssh_key,
+                cpu_count,
+                memory,
+                memory_headroom,
+                debug_mode,
+                &egress,
             )
             .await?;
-            debug!("{:?}", out);
-            Ok(())
+
+            match state.as_mut() {
+                Some(state) => {
+                    state.set_private_key(&ssh_key);
+                    state.record_deployment(
+                        &eif,
+                        out.enclave.cpu_count,
+                        out.enclave.memory_mib,
+                        debug_mode,
+                        &out.enclave.enclave_id,
+                        &egress,
+                    )?;
+                    state.save()?;
+                }
+                None => debug!("Stack '{}' is not recorded locally, skipping state.", name),
+            }
+            cli.output.emit(&out)
         }
         Commands::Logs { name, ssh_key } => {
-            let shared_config = aws_config::from_env().load().await;
-            let client = Client::new(&shared_config);
+            let state = StackState::load(&name)?;
+            let ssh_key = resolve_ssh_key(&name, ssh_key, &state)?;
+            let (client, _) = cloudformation_client(stack_region(&state)).await;
 
             info!("Viewing logs from enclave console '{}'.", name);
             info!("Enclave has to be in debug mode.");
-            logs(&client, &name, &ssh_key).await?;
+            logs(&client, &name, &ssh_key, cli.output).await?;
             Ok(())
         }
-        Commands::Delete { name } => {
-            let shared_config = aws_config::from_env().load().await;
-            let client = Client::new(&shared_config);
+        Commands::Attest {
+            name,
+            ssh_key,
+            eif,
+            attestation_port,
+        } => {
+            let state = StackState::load(&name)?;
+            let ssh_key = resolve_ssh_key(&name, ssh_key, &state)?;
+            let eif = eif
+                .or_else(|| Some(state.as_ref()?.deployment.as_ref()?.eif.clone()))
+                .unwrap_or_else(|| String::from("nitrogen.eif"));
+            let (client, _) = cloudformation_client(stack_region(&state)).await;
+
+            info!(eif, "Attesting enclave running on '{}'.", name);
+            let out = attest(&client, &name, &ssh_key, &eif, attestation_port).await?;
+            cli.output.emit(&out)?;
+            if out.verified {
+                info!("Enclave is running the measured EIF.");
+                Ok(())
+            } else {
+                Err(failure::err_msg(
+                    "Enclave PCRs do not match the build measurements.",
+                ))
+            }
+        }
+        Commands::Update {
+            name,
+            instance_type,
+            disk_size,
+            port,
+            ssh_location,
+            service_locations,
+            allow_replacement,
+        } => {
+            let mut state = StackState::load(&name)?;
+            let (client, _) = cloudformation_client(stack_region(&state)).await;
+
+            let ssh_location = match ssh_location {
+                Some(cidr) => Some(caller_ip::ssh_location(Some(cidr)).await?),
+                None => None,
+            };
+            info!("Updating enclave stack '{}'.", name);
+            let out = update(
+                &client,
+                &name,
+                instance_type.clone(),
+                disk_size,
+                port,
+                ssh_location.clone(),
+                service_locations.clone(),
+                allow_replacement,
+                &waiter,
+            )
+            .await?;
+            if let Some(state) = state.as_mut().filter(|_| out.executed) {
+                state.record_update(&out, instance_type, port, ssh_location, service_locations);
+                state.save()?;
+            }
+            cli.output.emit(&out)
+        }
+        Commands::SshAllow { name, cidr } => {
+            let mut state = StackState::load(&name)?;
+            let (client, _) = cloudformation_client(stack_region(&state)).await;
+            let ssh_location = caller_ip::ssh_location(cidr).await?;
+            let out = ssh_allow(&client, &name, &ssh_location, &waiter).await?;
+            if let Some(state) = state.as_mut().filter(|_| out.executed) {
+                state.ssh_location = ssh_location;
+                state.save()?;
+            }
+            cli.output.emit(&out)
+        }
+        Commands::PinHostKey { name, host_key } => {
+            let state = StackState::load(&name)?;
+            let (client, _) = cloudformation_client(stack_region(&state)).await;
+            let out = pin_host_key(&client, &name, &host_key).await?;
+            cli.output.emit(&out)
+        }
+        Commands::Delete { name, dry_run } => {
+            let state = StackState::load(&name)?;
+            let (client, _) = cloudformation_client(stack_region(&state)).await;
+            if dry_run {
+                return cli.output.print(&delete_dry_run(&client, &name).await?);
+            }
 
             info!("Deleting enclave stack '{}'.", name);
-            delete(&client, &name).await?;
-            Ok(())
+            let out = delete(&client, &name, &waiter).await?;
+            StackState::remove(&name)?;
+            cli.output.emit(&out)
+        }
+        Commands::List => {
+            let (client, _) = cloudformation_client(None).await;
+            let stacks = list(&client).await?;
+            cli.output.print(&ListOutput { stacks })
         }
+        Commands::Show { name } => match StackState::load(&name)? {
+            Some(state) => cli.output.print(&state),
+            None => Err(failure::err_msg(format!(
+                "No local state recorded for stack '{}'.",
+                name
+            ))),
+        },
         Commands::Start {
             service,
             public_key,
-            port,
+            ports,
+            service_locations,
             instance_type,
+            arch,
             disk_size,
             ssh_location,
             private_key,
+            on_failure,
+            egress,
         } => {
+            let started = Instant::now();
             let dockerfile =
                 Asset::get(&format!("{}/Dockerfile", service)).expect("unable to get dockerfile");
             let appsh = Asset::get(&format!("{}/app.sh", service)).expect("unable to get app.sh");
@@ -255,45 +669,158 @@ async fn main() -> Result<(), Error> {
             let dockerfile_path = &proj_dir.join("Dockerfile");
 
             create_file(dockerfile_path, dockerfile)?;
-            create_file(&proj_dir.join("run.sh"), appsh)?;
-            create_file(&proj_dir.join("app.sh"), runsh)?;
-
-            let ssh_location = ssh_location.to_string();
-            let instance_type = instance_type.to_string();
+            create_file(&proj_dir.join("run.sh"), runsh)?;
+            create_file(&proj_dir.join("app.sh"), appsh)?;
+            // Services that connect out of the enclave ship the egress helper
+            if let Some(egresssh) = Asset::get(&format!("{}/egress.sh", service)) {
+                create_file(&proj_dir.join("egress.sh"), egresssh)?;
+            }
+
+            let ssh_location = caller_ip::ssh_location(ssh_location).await?;
+            let instance_type =
+                instance_type.unwrap_or_else(|| arch.default_instance_type().to_string());
             let setup_template = SETUP_TEMPLATE.to_string();
-            let shared_config = aws_config::from_env().load().await;
-            let client = Client::new(&shared_config);
-            setup(
+            let (client, region) = cloudformation_client(None).await;
+            let setup_out = setup(
                 &client,
                 &setup_template,
                 &stack_name,
                 &instance_type,
                 &disk_size,
-                &port,
+                &ports,
+                &service_locations,
                 &public_key,
                 &ssh_location,
+                arch,
+                &Network::default(),
+                on_failure,
+                false,
+                &waiter,
             )
             .await?;
+            let mut state = StackState::new(
+                &setup_out,
+                region,
+                &instance_type,
+                &ports,
+                &service_locations,
+                &ssh_location,
+                &public_key,
+            );
+            state.set_private_key(&private_key);
+            info!(
+                name = stack_name,
+                state = %state.save()?.display(),
+                "Recorded stack state."
+            );
 
             // TODO should save this somewhere else than their current directory
             let eif_path = &format!("{}.eif", service);
 
-            build(
-                &proj_dir.to_str().unwrap().to_string(),
-                &"Dockerfile".to_string(),
+            let built_and_deployed = async {
+                let build_out = build(
+                    &proj_dir.to_str().unwrap().to_string(),
+                    &"Dockerfile".to_string(),
+                    eif_path,
+                    None,
+                    arch,
+                )
+                .await?;
+
+                let deploy_out = deploy(
+                    &client,
+                    &stack_name,
+                    eif_path,
+                    &private_key,
+                    2,
+                    None,
+                    DEFAULT_HEADROOM_MIB,
+                    false,
+                    &egress,
+                )
+                .await?;
+                Ok::<_, Error>((build_out, deploy_out))
+            }
+            .await;
+            let (build_out, deploy_out) = match built_and_deployed {
+                Ok(outs) => outs,
+                Err(err) if on_failure == OnFailure::Delete => {
+                    warn!(error = %err, "Start failed, tearing down stack '{}'.", stack_name);
+                    // The original error is what needs fixing, cleanup
+                    // failures are only logged
+                    if let Err(delete_err) = delete(&client, &stack_name, &waiter).await {
+                        error!(
+                            error = %delete_err,
+                            "Unable to tear down stack '{}', remove it with `nitrogen delete {}`.",
+                            stack_name,
+                            stack_name
+                        );
+                        return Err(err);
+                    }
+                    if let Err(remove_err) = StackState::remove(&stack_name) {
+                        warn!(error = %remove_err, "Unable to remove the state of stack '{}'.", stack_name);
+                    }
+                    // Build outputs may not exist if the build itself failed
+                    let _ = fs::remove_dir_all(&proj_dir);
+                    let _ = fs::remove_file(eif_path);
+                    let _ = fs::remove_file(BuildManifest::path_for(Path::new(eif_path)));
+                    return Err(err);
+                }
+                Err(err) => return Err(err),
+            };
+            state.record_deployment(
                 eif_path,
-            )
-            .await?;
+                deploy_out.enclave.cpu_count,
+                deploy_out.enclave.memory_mib,
+                false,
+                &deploy_out.enclave.enclave_id,
+                &egress,
+            )?;
+            state.save()?;
 
-            info!("Sleeping for 20s to give ec2 instance a chance to boot...");
-            tokio::time::sleep(Duration::from_secs(20)).await;
+            info!(
+                name = stack_name,
+                host = deploy_out.host,
+                enclave_id = deploy_out.enclave.enclave_id,
+                "Service deployed."
+            );
+            cli.output.emit(&StartOutput {
+                service,
+                setup: setup_out,
+                build: build_out,
+                deploy: deploy_out,
+                elapsed_secs: started.elapsed().as_secs_f64(),
+            })
+        }
+    }
+}
 
-            let out = deploy(&client, &stack_name, eif_path, &private_key, 2, None, false).await?;
+/// CloudFormation client for `region`, or the region configured in the environment.
+async fn cloudformation_client(region: Option<String>) -> (Client, Option<String>) {
+    let mut loader = aws_config::from_env();
+    if let Some(region) = region {
+        loader = loader.region(Region::new(region));
+    }
+    let shared_config = loader.load().await;
+    let region = shared_config.region().map(|r| r.to_string());
+    (Client::new(&shared_config), region)
+}
 
-            info!("{:?}", out);
+fn stack_region(state: &Option<StackState>) -> Option<String> {
+    state.as_ref()?.region.clone()
+}
 
-            Ok(())
-        }
+fn resolve_ssh_key(
+    name: &str,
+    ssh_key: Option<String>,
+    state: &Option<StackState>,
+) -> Result<String, Error> {
+    match ssh_key.or_else(|| state.as_ref()?.private_key.clone()) {
+        Some(ssh_key) => Ok(ssh_key),
+        None => Err(failure::err_msg(format!(
+            "No SSH private key given and none recorded for stack '{}'.",
+            name
+        ))),
     }
 }
 
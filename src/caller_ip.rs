@@ -0,0 +1,162 @@
+use crate::ingress::Source;
+use failure::Error;
+use hyper::{body, Client, StatusCode};
+use hyper_rustls::HttpsConnector;
+use std::net::IpAddr;
+use std::time::Duration;
+use tracing::info;
+
+/// Echoes the address a request came from, as plain text.
+const CHECKIP_URL: &str = "https://checkip.amazonaws.com";
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Public egress IP of this machine, as seen by AWS. Asked over HTTPS, so
+/// nothing on the path can hand out a different address to allow SSH from.
+pub async fn detect() -> Result<IpAddr, Error> {
+    let client = Client::builder().build::<_, hyper::Body>(HttpsConnector::with_native_roots());
+    let request = async {
+        let response = client.get(CHECKIP_URL.parse()?).await?;
+        let status = response.status();
+        let body = body::to_bytes(response.into_body()).await?;
+        Ok::<_, Error>((status, body))
+    };
+    let (status, body) = match tokio::time::timeout(TIMEOUT, request).await {
+        Ok(response) => response?,
+        Err(_) => {
+            return Err(failure::err_msg(format!(
+                "{} did not answer within {}s",
+                CHECKIP_URL,
+                TIMEOUT.as_secs()
+            )))
+        }
+    };
+    parse_response(status, &body)
+}
+
+/// The IP a checkip response body holds, a single address and a newline.
+fn parse_response(status: StatusCode, body: &[u8]) -> Result<IpAddr, Error> {
+    if status != StatusCode::OK {
+        return Err(failure::err_msg(format!(
+            "{} answered '{}'",
+            CHECKIP_URL, status
+        )));
+    }
+    let body = String::from_utf8_lossy(body);
+    match body.trim().parse() {
+        Ok(ip) => Ok(ip),
+        Err(_) => Err(failure::err_msg(format!(
+            "{} answered '{}', which is not an IP address",
+            CHECKIP_URL,
+            body.trim()
+        ))),
+    }
+}
+
+/// Source CIDR range for SSH: `explicit` if given, else this machine's public
+/// IP. The template only accepts IPv4 ranges.
+pub async fn ssh_location(explicit: Option<String>) -> Result<String, Error> {
+    if let Some(cidr) = explicit {
+        return match cidr.parse() {
+            Ok(Source::Ipv4(cidr)) => Ok(cidr),
+            _ => Err(failure::err_msg(format!(
+                "'{}' is not an IPv4 CIDR range of the form x.x.x.x/x.",
+                cidr
+            ))),
+        };
+    }
+    detected_ssh_location(detect().await)
+}
+
+/// `/32` range of the detected IP, which must be IPv4.
+fn detected_ssh_location(detected: Result<IpAddr, Error>) -> Result<String, Error> {
+    match detected {
+        Ok(IpAddr::V4(ip)) => {
+            info!(
+                "Allowing SSH from {}/32, this machine's public IP. Pass --ssh-location to \
+                override.",
+                ip
+            );
+            Ok(format!("{}/32", ip))
+        }
+        Ok(IpAddr::V6(ip)) => Err(failure::err_msg(format!(
+            "This machine's public IP {} is IPv6, which SSH ingress does not support. \
+            Pass --ssh-location <x.x.x.x/x> explicitly.",
+            ip
+        ))),
+        Err(err) => Err(failure::err_msg(format!(
+            "Unable to detect this machine's public IP ({}). Pass --ssh-location <x.x.x.x/x> \
+            explicitly, or 0.0.0.0/0 to allow SSH from anywhere.",
+            err
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_checkip_response() {
+        assert_eq!(
+            parse_response(StatusCode::OK, b"203.0.113.7\n").unwrap(),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            parse_response(StatusCode::OK, b"2001:db8::7\n").unwrap(),
+            "2001:db8::7".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_garbage_and_errors() {
+        for body in [
+            &b""[..],
+            b"\n",
+            b"<html>Proxy login</html>",
+            b"203.0.113",
+            b"203.0.113.7/32",
+            b"203.0.113.7, 198.51.100.1",
+            b"\xff\xfe",
+        ] {
+            assert!(parse_response(StatusCode::OK, body).is_err(), "{:?}", body);
+        }
+        let err = parse_response(StatusCode::SERVICE_UNAVAILABLE, b"203.0.113.7").unwrap_err();
+        assert!(err.to_string().contains("503"), "{}", err);
+    }
+
+    #[test]
+    fn detected_ips_must_be_ipv4() {
+        let detected = parse_response(StatusCode::OK, b"203.0.113.7\n");
+        assert_eq!(detected_ssh_location(detected).unwrap(), "203.0.113.7/32");
+
+        let detected = parse_response(StatusCode::OK, b"2001:db8::7\n");
+        let err = detected_ssh_location(detected).unwrap_err().to_string();
+        assert!(err.contains("is IPv6"), "{}", err);
+
+        let detected = parse_response(StatusCode::OK, b"garbage");
+        let err = detected_ssh_location(detected).unwrap_err().to_string();
+        assert!(err.contains("Pass --ssh-location"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn explicit_ssh_locations_must_be_ipv4_ranges() {
+        assert_eq!(
+            ssh_location(Some("203.0.113.0/24".to_string()))
+                .await
+                .unwrap(),
+            "203.0.113.0/24"
+        );
+        assert_eq!(
+            ssh_location(Some("0.0.0.0/0".to_string())).await.unwrap(),
+            "0.0.0.0/0"
+        );
+        for cidr in ["2001:db8::/32", "sg-0123abcd", "203.0.113.7", "garbage", ""] {
+            let err = ssh_location(Some(cidr.to_string())).await.unwrap_err();
+            assert!(
+                err.to_string().contains("is not an IPv4 CIDR range"),
+                "{}",
+                cidr
+            );
+        }
+    }
+}
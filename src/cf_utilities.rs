@@ -1,16 +1,76 @@
+use crate::enclave::EnclaveDescription;
+use crate::remote::RemoteHost;
 use aws_sdk_cloudformation::{
-    model::{Output as CloudOutput, Stack, StackStatus},
+    model::{ChangeSetStatus, Stack, StackStatus},
     Client,
 };
+use aws_smithy_types::date_time::Format;
 use failure::Error;
-use serde_json::{from_slice, json, Value};
-use std::process::Command;
-use tracing::{debug, info};
+use serde::{Deserialize, Serialize};
+use serde_json::from_slice;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+/// The stack called `name`, or `None` if there is none.
+pub async fn find_stack(client: &Client, name: &str) -> Result<Option<Stack>, Error> {
+    match client.describe_stacks().stack_name(name).send().await {
+        Ok(resp) => Ok(resp.stacks().unwrap_or_default().first().cloned()),
+        // CloudFormation reports a missing stack as a validation error
+        Err(err) if err.to_string().contains("does not exist") => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
 
 pub(crate) async fn get_stack(client: &Client, stack_id: &str) -> Result<Stack, Error> {
     let resp = client.describe_stacks().stack_name(stack_id).send().await?;
-    let this_stack = resp.stacks().unwrap_or_default().first().unwrap();
-    Ok(this_stack.clone())
+    match resp.stacks().unwrap_or_default().first() {
+        Some(stack) => Ok(stack.clone()),
+        None => Err(failure::err_msg(format!(
+            "CloudFormation returned no stack named '{}'.",
+            stack_id
+        ))),
+    }
+}
+
+/// Every stack nitrogen creates is tagged with this key so it can be found again.
+pub const MANAGED_TAG: &str = "nitrogen:managed";
+
+/// All stacks visible in the client's region, following pagination.
+pub(crate) async fn describe_all_stacks(client: &Client) -> Result<Vec<Stack>, Error> {
+    let mut stacks = vec![];
+    let mut next_token = None;
+    loop {
+        let resp = client
+            .describe_stacks()
+            .set_next_token(next_token)
+            .send()
+            .await?;
+        stacks.extend_from_slice(resp.stacks().unwrap_or_default());
+        match resp.next_token() {
+            Some(token) => next_token = Some(token.to_string()),
+            None => return Ok(stacks),
+        }
+    }
+}
+
+pub(crate) fn is_managed(stack: &Stack) -> bool {
+    stack
+        .tags()
+        .unwrap_or_default()
+        .iter()
+        .any(|tag| tag.key() == Some(MANAGED_TAG))
+}
+
+pub(crate) fn stack_parameter<'a>(stack: &'a Stack, key: &str) -> Option<&'a str> {
+    stack
+        .parameters()
+        .unwrap_or_default()
+        .iter()
+        .find(|p| p.parameter_key() == Some(key))?
+        .parameter_value()
 }
 
 pub(crate) async fn check_stack_status(
@@ -18,74 +78,781 @@ pub(crate) async fn check_stack_status(
     stack_id: &str,
 ) -> Result<(StackStatus, String), Error> {
     let this_stack = get_stack(client, stack_id).await?;
-    let stack_status = this_stack.stack_status().unwrap();
+    let stack_status = this_stack.stack_status().ok_or_else(|| {
+        failure::err_msg(format!(
+            "CloudFormation returned no status for stack '{}'.",
+            stack_id
+        ))
+    })?;
     let stack_status_reason = this_stack.stack_status_reason().unwrap_or("");
     Ok((stack_status.clone(), stack_status_reason.to_string()))
 }
 
-pub(crate) async fn get_instance_url(stack: &Stack) -> Result<String, Error> {
-    let outputs: Vec<&CloudOutput> = stack
-        .outputs()
-        .unwrap_or_default()
-        .iter()
-        .filter(|x| {
-            if x.output_key().unwrap_or_default() == "PublicDNS" {
-                return true;
+/// A resource status change reported by CloudFormation.
+#[derive(Clone, Debug, Serialize)]
+pub struct StackEvent {
+    pub timestamp: Option<String>,
+    pub logical_resource_id: String,
+    pub resource_type: String,
+    pub resource_status: String,
+    pub reason: Option<String>,
+}
+
+impl StackEvent {
+    pub fn is_failure(&self) -> bool {
+        self.resource_status.ends_with("_FAILED")
+    }
+}
+
+impl fmt::Display for StackEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}) {}",
+            self.logical_resource_id, self.resource_type, self.resource_status
+        )?;
+        if let Some(reason) = &self.reason {
+            write!(f, ": {}", reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// Follows the events of a stack, logging each one once as it appears.
+pub(crate) struct StackEvents {
+    stack_id: String,
+    seen: HashSet<String>,
+    /// Earliest failed resource event, usually the cause of the others.
+    pub first_failure: Option<StackEvent>,
+}
+
+impl StackEvents {
+    /// Follow a stack that was just created, so all its events are new.
+    pub fn new(stack_id: &str) -> Self {
+        StackEvents {
+            stack_id: stack_id.to_string(),
+            seen: HashSet::new(),
+            first_failure: None,
+        }
+    }
+
+    /// Follow an existing stack, skipping the events of earlier operations.
+    pub async fn since_now(client: &Client, stack_id: &str) -> Result<Self, Error> {
+        let mut events = Self::new(stack_id);
+        let resp = client
+            .describe_stack_events()
+            .stack_name(stack_id)
+            .send()
+            .await?;
+        for event in resp.stack_events().unwrap_or_default() {
+            if let Some(id) = event.event_id() {
+                events.seen.insert(id.to_string());
             }
-            false
-        })
-        .collect();
-
-    if outputs.is_empty() {
-        return Err(failure::err_msg("unable to query public dns"));
-    }
-    let instance_url = outputs[0].output_value().unwrap_or_default();
-    Ok(instance_url.to_string())
-}
-
-pub(crate) fn describe_enclave(ssh_key: &str, url: &str) -> Result<Value, Error> {
-    let describe_out = Command::new("ssh")
-        .args([
-            "-i",
-            ssh_key,
-            format!("ec2-user@{}", url).as_str(),
-            "nitro-cli",
-            "describe-enclaves",
-        ])
-        .output()?;
-    debug!(stdout=?describe_out);
-
-    if !describe_out.status.success() {
-        return Err(failure::err_msg(format!(
-            "failed to get enclave info{:?}",
-            describe_out
-        )));
-    };
+        }
+        Ok(events)
+    }
 
-    let json: Value = match from_slice(&describe_out.stdout) {
-        Ok(json) => json,
-        Err(_) => return Err(failure::err_msg("Could not parse AWS response.")),
-    };
+    /// Log events that appeared since the last poll, oldest first.
+    pub async fn poll(&mut self, client: &Client) -> Result<(), Error> {
+        let mut new_events = vec![];
+        let mut next_token = None;
+        // Events come newest first, so stop at the first one already logged
+        'pages: loop {
+            let resp = client
+                .describe_stack_events()
+                .stack_name(&self.stack_id)
+                .set_next_token(next_token)
+                .send()
+                .await?;
+            for event in resp.stack_events().unwrap_or_default() {
+                let id = event.event_id().unwrap_or_default();
+                if self.seen.contains(id) {
+                    break 'pages;
+                }
+                self.seen.insert(id.to_string());
+                new_events.push(StackEvent {
+                    timestamp: event.timestamp().and_then(|t| t.fmt(Format::DateTime).ok()),
+                    logical_resource_id: event
+                        .logical_resource_id()
+                        .unwrap_or_default()
+                        .to_string(),
+                    resource_type: event.resource_type().unwrap_or_default().to_string(),
+                    resource_status: event
+                        .resource_status()
+                        .map(|s| s.as_str().to_string())
+                        .unwrap_or_default(),
+                    reason: event.resource_status_reason().map(str::to_string),
+                });
+            }
+            match resp.next_token() {
+                Some(token) => next_token = Some(token.to_string()),
+                None => break,
+            }
+        }
+
+        for event in new_events.into_iter().rev() {
+            if event.is_failure() {
+                warn!(
+                    resource_type = event.resource_type,
+                    reason = event.reason.as_deref().unwrap_or_default(),
+                    "{} {}",
+                    event.logical_resource_id,
+                    event.resource_status
+                );
+                if self.first_failure.is_none() {
+                    self.first_failure = Some(event);
+                }
+            } else {
+                info!(
+                    resource_type = event.resource_type,
+                    "{} {}", event.logical_resource_id, event.resource_status
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Describe why an operation failed, from the first failed resource if any.
+    pub(crate) fn failure(&self, operation: StackOperation, outcome: &StackOutcome) -> Error {
+        let what = format!("{} of stack {}", operation, self.stack_id);
+        match &self.first_failure {
+            Some(event) => failure::err_msg(format!(
+                "{} ended in {}. First failure: {}",
+                what,
+                outcome.status.as_str(),
+                event
+            )),
+            None => failure::err_msg(format!(
+                "{} ended in {}: {}",
+                what,
+                outcome.status.as_str(),
+                outcome.status_reason
+            )),
+        }
+    }
+}
+
+/// The kind of stack operation being waited for, which decides what its
+/// statuses mean.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StackOperation {
+    Create,
+    Update,
+    Delete,
+}
+
+/// Where a stack operation stands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StackProgress {
+    InProgress,
+    Succeeded,
+    /// The operation failed and CloudFormation undid it.
+    RolledBack,
+    Failed,
+}
+
+impl StackOperation {
+    pub fn classify(&self, status: &StackStatus) -> StackProgress {
+        use StackStatus::*;
+        match (self, status) {
+            (StackOperation::Create, CreateInProgress | RollbackInProgress | DeleteInProgress) => {
+                StackProgress::InProgress
+            }
+            (StackOperation::Create, CreateComplete) => StackProgress::Succeeded,
+            // Deleted when created with `--on-failure DELETE`
+            (StackOperation::Create, RollbackComplete | DeleteComplete) => {
+                StackProgress::RolledBack
+            }
+            (
+                StackOperation::Update,
+                UpdateInProgress
+                | UpdateCompleteCleanupInProgress
+                | UpdateRollbackInProgress
+                | UpdateRollbackCompleteCleanupInProgress,
+            ) => StackProgress::InProgress,
+            (StackOperation::Update, UpdateComplete) => StackProgress::Succeeded,
+            (StackOperation::Update, UpdateRollbackComplete) => StackProgress::RolledBack,
+            (StackOperation::Delete, DeleteInProgress) => StackProgress::InProgress,
+            (StackOperation::Delete, DeleteComplete) => StackProgress::Succeeded,
+            // CreateFailed, RollbackFailed, UpdateFailed, UpdateRollbackFailed,
+            // DeleteFailed and anything unexpected for the operation
+            _ => StackProgress::Failed,
+        }
+    }
+}
+
+impl fmt::Display for StackOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StackOperation::Create => write!(f, "Creation"),
+            StackOperation::Update => write!(f, "Update"),
+            StackOperation::Delete => write!(f, "Deletion"),
+        }
+    }
+}
+
+/// Final status of a stack operation that ran to completion.
+#[derive(Clone, Debug)]
+pub(crate) struct StackOutcome {
+    pub status: StackStatus,
+    pub status_reason: String,
+    pub progress: StackProgress,
+}
+
+/// `Timeout` of the `EC2Instance` creation policy in the setup template.
+const INSTANCE_SIGNAL_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+/// `Timeout` of the `HostKeyWaitCondition`, which starts once the instance is created.
+const HOST_KEY_TIMEOUT: Duration = Duration::from_secs(900);
+/// Time for the other resources and for CloudFormation to start a rollback.
+const WAIT_MARGIN: Duration = Duration::from_secs(5 * 60);
+/// Longest a stack creation can take before CloudFormation itself gives up.
+pub const DEFAULT_WAIT_TIMEOUT: Duration = INSTANCE_SIGNAL_TIMEOUT
+    .saturating_add(HOST_KEY_TIMEOUT)
+    .saturating_add(WAIT_MARGIN);
+
+/// What a Ctrl-C would interrupt, for the handler installed by [`exit_on_interrupt`].
+static INTERRUPTING: Mutex<Option<String>> = Mutex::new(None);
+
+/// Exit with the conventional status 130 on Ctrl-C, saying what was left
+/// running. Installed once, as tokio keeps handling SIGINT for the rest of the
+/// process once anything listens for it.
+pub fn exit_on_interrupt() {
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        match INTERRUPTING.lock().ok().and_then(|waiting| waiting.clone()) {
+            Some(waiting) => error!("Interrupted while {}", waiting),
+            None => error!("Interrupted."),
+        }
+        std::process::exit(130);
+    });
+}
+
+/// Describes what a Ctrl-C would interrupt until dropped.
+struct Interrupting;
+
+impl Interrupting {
+    fn set(waiting: String) -> Self {
+        if let Ok(mut interrupting) = INTERRUPTING.lock() {
+            *interrupting = Some(waiting);
+        }
+        Interrupting
+    }
+}
+
+impl Drop for Interrupting {
+    fn drop(&mut self) {
+        if let Ok(mut interrupting) = INTERRUPTING.lock() {
+            *interrupting = None;
+        }
+    }
+}
+
+/// Polls a stack until an operation on it completes, streaming its events.
+/// Polling backs off exponentially, up to `max_interval`.
+#[derive(Clone, Debug)]
+pub struct StackWaiter {
+    pub timeout: Duration,
+    pub initial_interval: Duration,
+    pub max_interval: Duration,
+}
 
-    let description = match json.as_array() {
-        Some(enclaves) => match enclaves.get(0) {
-            Some(enclave) => enclave,
-            None => return Err(failure::err_msg("Enclave not created.")),
-        },
-        None => return Err(failure::err_msg("Enclave not created.")),
+impl Default for StackWaiter {
+    fn default() -> Self {
+        StackWaiter {
+            timeout: DEFAULT_WAIT_TIMEOUT,
+            initial_interval: Duration::from_secs(2),
+            max_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+impl StackWaiter {
+    pub fn with_timeout(timeout: Duration) -> Self {
+        StackWaiter {
+            timeout,
+            ..Default::default()
+        }
+    }
+
+    /// Wait for `operation` to reach a terminal status. Timing out or Ctrl-C
+    /// stop nitrogen only, the stack is left as it is.
+    pub(crate) async fn wait(
+        &self,
+        client: &Client,
+        events: &mut StackEvents,
+        operation: StackOperation,
+    ) -> Result<StackOutcome, Error> {
+        let started = Instant::now();
+        let mut interval = self.initial_interval;
+        let _interrupting = Interrupting::set(format!(
+            "waiting for stack {}. The stack was left as it is, CloudFormation carries on \
+            with the {}.",
+            events.stack_id,
+            operation.to_string().to_lowercase()
+        ));
+        loop {
+            events.poll(client).await?;
+            let (status, status_reason) = check_stack_status(client, &events.stack_id).await?;
+            let progress = operation.classify(&status);
+            if progress != StackProgress::InProgress {
+                debug!(
+                    status = status.as_str(),
+                    ?progress,
+                    "Stack operation completed."
+                );
+                return Ok(StackOutcome {
+                    status,
+                    status_reason,
+                    progress,
+                });
+            }
+
+            let remaining = self.timeout.saturating_sub(started.elapsed());
+            if remaining.is_zero() {
+                return Err(failure::err_msg(format!(
+                    "{} of stack {} did not complete within {}s, it is still {}. \
+                    The stack was left as it is.",
+                    operation,
+                    events.stack_id,
+                    self.timeout.as_secs(),
+                    status.as_str()
+                )));
+            }
+            tokio::time::sleep(interval.min(remaining)).await;
+            interval = (interval * 3 / 2).min(self.max_interval);
+        }
+    }
+}
+
+/// One resource a change set would add, modify or remove.
+#[derive(Clone, Debug, Serialize)]
+pub struct ResourceChange {
+    pub action: String,
+    pub logical_resource_id: String,
+    pub resource_type: String,
+    /// `True`, `False` or `Conditional`; replaced resources get new physical ids.
+    pub replacement: Option<String>,
+    /// Properties whose change causes the update.
+    pub properties: Vec<String>,
+}
+
+impl ResourceChange {
+    pub fn is_replaced(&self) -> bool {
+        matches!(
+            self.replacement.as_deref(),
+            Some("True") | Some("Conditional")
+        )
+    }
+}
+
+/// Wait for a change set to be computed and list its resource changes.
+/// `None` means CloudFormation found nothing to change. The wait is bounded
+/// like `waiter`'s, and Ctrl-C leaves the change set as it is.
+pub(crate) async fn describe_change_set(
+    client: &Client,
+    stack_name: &str,
+    change_set_id: &str,
+    waiter: &StackWaiter,
+) -> Result<Option<Vec<ResourceChange>>, Error> {
+    let started = Instant::now();
+    let mut interval = waiter.initial_interval;
+    let _interrupting = Interrupting::set(format!(
+        "waiting for the change set for stack '{}', which was left as it is.",
+        stack_name
+    ));
+    let mut changes = vec![];
+    let mut next_token = None;
+    loop {
+        let resp = client
+            .describe_change_set()
+            .stack_name(stack_name)
+            .change_set_name(change_set_id)
+            .set_next_token(next_token.clone())
+            .send()
+            .await?;
+        match resp.status() {
+            Some(ChangeSetStatus::CreateComplete) => {}
+            Some(ChangeSetStatus::Failed) => {
+                let reason = resp.status_reason().unwrap_or_default();
+                // CloudFormation fails change sets that would not change anything
+                if reason.contains("didn't contain changes") || reason.contains("No updates") {
+                    return Ok(None);
+                }
+                return Err(failure::err_msg(format!(
+                    "Change set for stack '{}' failed: {}",
+                    stack_name, reason
+                )));
+            }
+            status => {
+                let status = status.map(|s| s.as_str()).unwrap_or("pending");
+                let remaining = waiter.timeout.saturating_sub(started.elapsed());
+                if remaining.is_zero() {
+                    return Err(failure::err_msg(format!(
+                        "Change set for stack '{}' was not computed within {}s, it is still {}.",
+                        stack_name,
+                        waiter.timeout.as_secs(),
+                        status
+                    )));
+                }
+                tokio::time::sleep(interval.min(remaining)).await;
+                interval = (interval * 3 / 2).min(waiter.max_interval);
+                continue;
+            }
+        }
+        for change in resp.changes().unwrap_or_default() {
+            let resource = match change.resource_change() {
+                Some(resource) => resource,
+                None => continue,
+            };
+            changes.push(ResourceChange {
+                action: resource
+                    .action()
+                    .map(|a| a.as_str().to_string())
+                    .unwrap_or_default(),
+                logical_resource_id: resource
+                    .logical_resource_id()
+                    .unwrap_or_default()
+                    .to_string(),
+                resource_type: resource.resource_type().unwrap_or_default().to_string(),
+                replacement: resource.replacement().map(|r| r.as_str().to_string()),
+                properties: resource
+                    .details()
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|detail| detail.target()?.name().map(str::to_string))
+                    .collect(),
+            });
+        }
+        match resp.next_token() {
+            Some(token) => next_token = Some(token.to_string()),
+            None => return Ok(Some(changes)),
+        }
+    }
+}
+
+/// Outputs of a Nitrogen stack, looked up by their CloudFormation output key
+/// since CloudFormation does not guarantee the order they are returned in.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StackOutputs {
+    pub stack_id: String,
+    pub instance_id: String,
+    /// `None` for instances launched with `--private`.
+    pub public_ip: Option<String>,
+    pub availability_zone: String,
+    pub public_dns: Option<String>,
+    /// `None` for stacks predating VPC support.
+    pub private_ip: Option<String>,
+    pub private_dns: Option<String>,
+    /// SSH host key of the instance, `None` for stacks predating host key pinning.
+    pub host_key: Option<String>,
+}
+
+impl StackOutputs {
+    pub fn from_stack(stack: &Stack) -> Result<Self, Error> {
+        let stack_name = stack.stack_name().unwrap_or_default();
+        let outputs: HashMap<&str, &str> = stack
+            .outputs()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|o| Some((o.output_key()?, o.output_value()?)))
+            .collect();
+        let get = |key: &str| match outputs.get(key) {
+            Some(value) => Ok(value.to_string()),
+            None => Err(failure::err_msg(format!(
+                "Stack '{}' is missing the `{}` output, it may not have been created by Nitrogen.",
+                stack_name, key
+            ))),
+        };
+
+        let optional = |key: &str| outputs.get(key).map(|value| value.to_string());
+
+        let outputs = StackOutputs {
+            stack_id: stack.stack_id().unwrap_or_default().to_string(),
+            instance_id: get("InstanceId")?,
+            public_ip: optional("PublicIP"),
+            availability_zone: get("AZ")?,
+            public_dns: optional("PublicDNS"),
+            private_ip: optional("PrivateIP"),
+            private_dns: optional("PrivateDNS"),
+            host_key: match outputs.get("HostKey") {
+                Some(data) => Some(parse_wait_condition_data(data, "HostKey")?),
+                None => None,
+            },
+        };
+        if outputs.public_dns.is_none() && outputs.private_ip.is_none() {
+            return Err(failure::err_msg(format!(
+                "Stack '{}' has neither a `PublicDNS` nor a `PrivateIP` output, it may not have \
+                been created by Nitrogen.",
+                stack_name
+            )));
+        }
+        Ok(outputs)
+    }
+
+    /// Address to reach the instance at: its public DNS name, or its private
+    /// IP for instances without a public one, which needs a route into the VPC.
+    pub fn host(&self) -> &str {
+        self.public_dns
+            .as_deref()
+            .or(self.private_ip.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// Every address the instance is known by, for pinning its host key.
+    pub fn addresses(&self) -> Vec<&str> {
+        [
+            &self.public_dns,
+            &self.public_ip,
+            &self.private_dns,
+            &self.private_ip,
+        ]
+        .into_iter()
+        .filter_map(|address| address.as_deref())
+        .collect()
+    }
+}
+
+/// The `Data` attribute of a wait condition is a JSON object mapping each
+/// signal's unique id to the data it was sent with.
+fn parse_wait_condition_data(data: &str, id: &str) -> Result<String, Error> {
+    let signals: HashMap<String, String> = match serde_json::from_str(data) {
+        Ok(signals) => signals,
+        Err(_) => {
+            return Err(failure::err_msg(format!(
+                "Unable to parse wait condition data '{}'.",
+                data
+            )))
+        }
     };
+    match signals.get(id) {
+        Some(value) => Ok(value.to_string()),
+        None => Err(failure::err_msg(format!(
+            "Wait condition data is missing the `{}` signal.",
+            id
+        ))),
+    }
+}
 
-    Ok(description.clone())
+pub(crate) async fn get_stack_outputs(
+    client: &Client,
+    stack_id: &str,
+) -> Result<StackOutputs, Error> {
+    let this_stack = get_stack(client, stack_id).await?;
+    StackOutputs::from_stack(&this_stack)
 }
 
-pub(crate) fn check_enclave_status(ssh_key: &str, url: &str) -> Result<(), Error> {
+pub(crate) fn describe_enclaves(remote: &RemoteHost) -> Result<Vec<EnclaveDescription>, Error> {
+    let describe_out = remote.exec("nitro-cli describe-enclaves")?;
+    debug!(stdout = %String::from_utf8_lossy(&describe_out.stdout));
+
+    match from_slice(&describe_out.stdout) {
+        Ok(enclaves) => Ok(enclaves),
+        Err(_) => Err(failure::err_msg("Could not parse AWS response.")),
+    }
+}
+
+pub(crate) fn describe_enclave(remote: &RemoteHost) -> Result<EnclaveDescription, Error> {
+    match describe_enclaves(remote)?.into_iter().next() {
+        Some(enclave) => Ok(enclave),
+        None => Err(failure::err_msg("Enclave not created.")),
+    }
+}
+
+pub(crate) fn check_enclave_status(remote: &RemoteHost) -> Result<EnclaveDescription, Error> {
     info!("Check enclave status...");
 
-    match describe_enclave(ssh_key, url)?.get("State") {
-        // According to the docs, the state is either "running" or "terminating"
-        // https://docs.aws.amazon.com/enclaves/latest/user/cmd-nitro-describe-enclaves.html
-        Some(x) if x.eq(&json!("RUNNING")) => Ok(()),
-        Some(x) => Err(failure::err_msg(format!("Enclave created, but is {}.", x))),
-        None => Err(failure::err_msg("Enclave created, but unknown state.")),
+    let enclave = describe_enclave(remote)?;
+    if enclave.is_running() {
+        Ok(enclave)
+    } else {
+        Err(failure::err_msg(format!(
+            "Enclave created, but is {}.",
+            enclave.state
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_cloudformation::model::Output;
+
+    fn stack(outputs: &[(&str, &str)]) -> Stack {
+        let outputs = outputs
+            .iter()
+            .map(|(key, value)| {
+                Output::builder()
+                    .output_key(*key)
+                    .output_value(*value)
+                    .build()
+            })
+            .collect();
+        Stack::builder()
+            .stack_name("test")
+            .stack_id("arn:aws:cloudformation:us-east-1:123456789012:stack/test/1")
+            .set_outputs(Some(outputs))
+            .build()
+    }
+
+    const HOST_KEY: &str = r#"{"HostKey":"ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA"}"#;
+
+    #[test]
+    fn reads_stack_outputs() {
+        let outputs = StackOutputs::from_stack(&stack(&[
+            ("InstanceId", "i-0123456789abcdef0"),
+            ("AZ", "us-east-1a"),
+            ("PublicDNS", "ec2-203-0-113-1.compute-1.amazonaws.com"),
+            ("PublicIP", "203.0.113.1"),
+            ("PrivateIP", "10.0.0.5"),
+            ("HostKey", HOST_KEY),
+        ]))
+        .unwrap();
+        assert_eq!(outputs.instance_id, "i-0123456789abcdef0");
+        assert_eq!(outputs.availability_zone, "us-east-1a");
+        assert_eq!(outputs.host(), "ec2-203-0-113-1.compute-1.amazonaws.com");
+        assert_eq!(
+            outputs.addresses(),
+            [
+                "ec2-203-0-113-1.compute-1.amazonaws.com",
+                "203.0.113.1",
+                "10.0.0.5"
+            ]
+        );
+        assert_eq!(
+            outputs.host_key.as_deref(),
+            Some("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA")
+        );
+    }
+
+    #[test]
+    fn private_stacks_are_reached_by_ip() {
+        let outputs = StackOutputs::from_stack(&stack(&[
+            ("InstanceId", "i-0123456789abcdef0"),
+            ("AZ", "us-east-1a"),
+            ("PrivateIP", "10.0.0.5"),
+        ]))
+        .unwrap();
+        assert_eq!(outputs.host(), "10.0.0.5");
+        assert_eq!(outputs.public_dns, None);
+        assert_eq!(outputs.host_key, None);
+    }
+
+    #[test]
+    fn rejects_missing_outputs() {
+        let err =
+            StackOutputs::from_stack(&stack(&[("AZ", "us-east-1a"), ("PrivateIP", "10.0.0.5")]))
+                .unwrap_err()
+                .to_string();
+        assert!(
+            err.contains("'test' is missing the `InstanceId` output"),
+            "{}",
+            err
+        );
+
+        let err = StackOutputs::from_stack(&stack(&[
+            ("InstanceId", "i-0123456789abcdef0"),
+            ("AZ", "us-east-1a"),
+        ]))
+        .unwrap_err()
+        .to_string();
+        assert!(
+            err.contains("neither a `PublicDNS` nor a `PrivateIP`"),
+            "{}",
+            err
+        );
+
+        assert!(StackOutputs::from_stack(&stack(&[])).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_host_keys() {
+        let err = StackOutputs::from_stack(&stack(&[
+            ("InstanceId", "i-0123456789abcdef0"),
+            ("AZ", "us-east-1a"),
+            ("PrivateIP", "10.0.0.5"),
+            ("HostKey", "ssh-ed25519 AAAA"),
+        ]))
+        .unwrap_err()
+        .to_string();
+        assert!(
+            err.contains("Unable to parse wait condition data"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn parses_wait_condition_data() {
+        assert_eq!(
+            parse_wait_condition_data(r#"{"HostKey":"key","Other":"x"}"#, "HostKey").unwrap(),
+            "key"
+        );
+        let err = parse_wait_condition_data(r#"{"Other":"x"}"#, "HostKey").unwrap_err();
+        assert!(err.to_string().contains("missing the `HostKey` signal"));
+        for data in ["", "null", "[]", r#"{"HostKey":1}"#, "{"] {
+            assert!(
+                parse_wait_condition_data(data, "HostKey").is_err(),
+                "{}",
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn classifies_creation() {
+        use StackStatus::*;
+        let create = StackOperation::Create;
+        for status in [CreateInProgress, RollbackInProgress, DeleteInProgress] {
+            assert_eq!(create.classify(&status), StackProgress::InProgress);
+        }
+        assert_eq!(create.classify(&CreateComplete), StackProgress::Succeeded);
+        assert_eq!(
+            create.classify(&RollbackComplete),
+            StackProgress::RolledBack
+        );
+        assert_eq!(create.classify(&DeleteComplete), StackProgress::RolledBack);
+        for status in [CreateFailed, RollbackFailed, DeleteFailed, UpdateComplete] {
+            assert_eq!(create.classify(&status), StackProgress::Failed);
+        }
+    }
+
+    #[test]
+    fn classifies_updates() {
+        use StackStatus::*;
+        let update = StackOperation::Update;
+        for status in [
+            UpdateInProgress,
+            UpdateCompleteCleanupInProgress,
+            UpdateRollbackInProgress,
+            UpdateRollbackCompleteCleanupInProgress,
+        ] {
+            assert_eq!(update.classify(&status), StackProgress::InProgress);
+        }
+        assert_eq!(update.classify(&UpdateComplete), StackProgress::Succeeded);
+        assert_eq!(
+            update.classify(&UpdateRollbackComplete),
+            StackProgress::RolledBack
+        );
+        for status in [UpdateFailed, UpdateRollbackFailed, CreateComplete] {
+            assert_eq!(update.classify(&status), StackProgress::Failed);
+        }
+    }
+
+    #[test]
+    fn classifies_deletion() {
+        use StackStatus::*;
+        let delete = StackOperation::Delete;
+        assert_eq!(
+            delete.classify(&DeleteInProgress),
+            StackProgress::InProgress
+        );
+        assert_eq!(delete.classify(&DeleteComplete), StackProgress::Succeeded);
+        for status in [DeleteFailed, CreateComplete, RollbackComplete] {
+            assert_eq!(delete.classify(&status), StackProgress::Failed);
+        }
+        let unknown = StackStatus::Unknown("IMPORT_IN_PROGRESS".to_string());
+        assert_eq!(delete.classify(&unknown), StackProgress::Failed);
     }
 }
@@ -0,0 +1,94 @@
+use crate::attestation;
+use crate::cf_utilities as utilities;
+use crate::known_hosts;
+use crate::manifest::BuildManifest;
+use crate::output::AttestOutput;
+use crate::remote::RemoteHost;
+use aws_sdk_cloudformation::Client;
+use failure::Error;
+use rand::RngCore;
+use std::path::Path;
+use tracing::{info, instrument, warn};
+
+/// Vsock port the enclave's attestation responder listens on by default.
+pub const DEFAULT_ATTESTATION_PORT: u32 = 5005;
+
+/// Ask the enclave for an attestation document bound to `nonce`. The enclave
+/// is expected to answer a hex encoded nonce line on `port` with the raw
+/// COSE_Sign1 document produced by its NSM device.
+fn fetch_document(
+    remote: &RemoteHost,
+    enclave_cid: u64,
+    port: u32,
+    nonce: &[u8],
+) -> Result<Vec<u8>, Error> {
+    info!(
+        enclave_cid,
+        port, "Requesting attestation document from enclave."
+    );
+    let out = remote.exec(&format!(
+        "printf '%s\\n' {} | docker run --rm -i alpine/socat - VSOCK-CONNECT:{}:{}",
+        hex::encode(nonce),
+        enclave_cid,
+        port
+    ))?;
+    if out.stdout.is_empty() {
+        return Err(failure::err_msg(format!(
+            "Enclave returned no attestation document on vsock port {}.",
+            port
+        )));
+    }
+    Ok(out.stdout)
+}
+
+#[instrument(level = "debug", skip(client))]
+pub async fn attest(
+    client: &Client,
+    stack_name: &str,
+    ssh_key: &str,
+    eif: &str,
+    port: u32,
+) -> Result<AttestOutput, Error> {
+    let expected = BuildManifest::load_for(Path::new(eif))?.measurements;
+
+    let outputs = utilities::get_stack_outputs(client, stack_name).await?;
+    let known_hosts = known_hosts::for_stack(stack_name, &outputs)?;
+    let remote = RemoteHost::connect(outputs.host(), ssh_key, &known_hosts)?;
+    let enclave = utilities::check_enclave_status(&remote)?;
+
+    let mut nonce = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let cose = fetch_document(&remote, enclave.enclave_cid, port, &nonce)?;
+    let document = attestation::verify(&cose, &nonce)?;
+    info!(
+        module_id = document.module_id,
+        "Attestation document is signed by the AWS Nitro Enclaves root."
+    );
+
+    let pcrs = attestation::check_pcrs(&document, &expected);
+    for pcr in &pcrs {
+        if pcr.matches {
+            info!(
+                index = pcr.index,
+                value = pcr.expected,
+                "PCR matches build."
+            );
+        } else {
+            warn!(
+                index = pcr.index,
+                expected = pcr.expected,
+                actual = pcr.actual.as_deref().unwrap_or("-"),
+                "PCR does not match build."
+            );
+        }
+    }
+
+    Ok(AttestOutput {
+        name: stack_name.to_string(),
+        enclave_id: enclave.enclave_id,
+        eif: eif.to_string(),
+        verified: pcrs.iter().all(|pcr| pcr.matches),
+        document,
+        pcrs,
+    })
+}
@@ -1,17 +1,76 @@
+use crate::arch::Arch;
+use crate::eif::Eif;
+use crate::enclave::{BuildEnclaveOutput, Measurements};
+use crate::manifest::BuildManifest;
+use crate::output::BuildOutput;
+use crate::state;
+use aws_smithy_types::{date_time::Format, DateTime};
 use failure::Error;
 use home;
 use std::env;
+use std::fs;
 use std::path::PathBuf;
-use std::process::ExitStatus;
+use std::process::Stdio;
+use std::time::{Instant, SystemTime};
 use tokio::process::Command;
-use tracing::{info, instrument};
+use tracing::{debug, info, instrument};
+
+pub const EIF_BUILDER_IMAGE: &str = "capeprivacy/eif-builder:latest";
+
+/// Where the signing material is mounted inside the eif-builder container.
+const SIGNING_CERTIFICATE_MOUNT: &str = "/root/signing/certificate.pem";
+const SIGNING_KEY_MOUNT: &str = "/root/signing/key.pem";
+
+/// Certificate and private key to sign an EIF with. The hash of the
+/// certificate becomes the enclave's PCR8.
+#[derive(Clone, Debug)]
+pub struct Signing {
+    pub certificate: String,
+    pub private_key: String,
+}
+
+/// `build-enclave` reports the PCRs of the new image as JSON on stdout, possibly
+/// after some progress output.
+fn parse_measurements(stdout: &[u8]) -> Result<Measurements, Error> {
+    let start = stdout
+        .iter()
+        .position(|b| *b == b'{')
+        .unwrap_or(stdout.len());
+    match serde_json::Deserializer::from_slice(&stdout[start..])
+        .into_iter::<BuildEnclaveOutput>()
+        .next()
+    {
+        Some(Ok(output)) => Ok(output.measurements),
+        _ => Err(failure::err_msg(
+            "Unable to find the EIF measurements in the eif-builder output.",
+        )),
+    }
+}
+
+/// Digest of a local docker image, formatted with a `docker image inspect` template.
+async fn image_digest(image: &str, format: &str) -> Result<String, Error> {
+    let out = Command::new("docker")
+        .args(["image", "inspect", "--format", format, image])
+        .output()
+        .await?;
+    if !out.status.success() {
+        return Err(failure::err_msg(format!(
+            "Unable to inspect docker image {}.",
+            image
+        )));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
 
 #[instrument(level = "debug")]
 pub async fn build(
     dockerfile_dir: &String,
     dockerfile_name: &String,
     eif_name: &String,
-) -> Result<ExitStatus, Error> {
+    signing: Option<&Signing>,
+    arch: Arch,
+) -> Result<BuildOutput, Error> {
+    let started = Instant::now();
     let dockerdir = PathBuf::from(dockerfile_dir);
     let mut dockerfile_path = PathBuf::from(dockerfile_dir);
     dockerfile_path.push(dockerfile_name);
@@ -22,7 +81,7 @@ pub async fn build(
             "-t",
             "nitrogen-build",
             "--platform",
-            "linux/amd64",
+            arch.docker_platform(),
             dockerdir.to_str().unwrap(),
             "-f",
             dockerfile_path.to_str().unwrap(),
@@ -37,30 +96,106 @@ pub async fn build(
     let h = home::home_dir().unwrap_or_default();
     let cwd = env::current_dir()?;
     let eif_dir = cwd.to_str().unwrap_or_default();
-    let eif_builder_process = Command::new("docker")
-        .args([
-            "run",
-            "-v",
-            &format!("{}/.docker:/root/.docker", h.display()),
-            "-v",
-            "/var/run/docker.sock:/var/run/docker.sock",
-            "-v",
-            &format!("{}:/root/build", eif_dir,),
-            "capeprivacy/eif-builder:latest",
-            "build-enclave",
-            "--docker-uri",
-            "nitrogen-build",
-            "--output-file",
-            &format!("/root/build/{}", eif_name),
-        ])
+    let mut eif_builder = Command::new("docker");
+    // The builder takes the enclave kernel from its own platform
+    eif_builder.args([
+        "run",
+        "--platform",
+        arch.docker_platform(),
+        "-v",
+        &format!("{}/.docker:/root/.docker", h.display()),
+        "-v",
+        "/var/run/docker.sock:/var/run/docker.sock",
+        "-v",
+        &format!("{}:/root/build", eif_dir,),
+    ]);
+    let signing = match signing {
+        Some(signing) => {
+            let certificate = fs::canonicalize(&signing.certificate)?;
+            let private_key = fs::canonicalize(&signing.private_key)?;
+            eif_builder.args([
+                "-v",
+                &format!("{}:{}:ro", certificate.display(), SIGNING_CERTIFICATE_MOUNT),
+                "-v",
+                &format!("{}:{}:ro", private_key.display(), SIGNING_KEY_MOUNT),
+            ]);
+            Some(certificate.display().to_string())
+        }
+        None => None,
+    };
+    eif_builder.args([
+        EIF_BUILDER_IMAGE,
+        "build-enclave",
+        "--docker-uri",
+        "nitrogen-build",
+        "--output-file",
+        &format!("/root/build/{}", eif_name),
+    ]);
+    if signing.is_some() {
+        eif_builder.args([
+            "--signing-certificate",
+            SIGNING_CERTIFICATE_MOUNT,
+            "--private-key",
+            SIGNING_KEY_MOUNT,
+        ]);
+    }
+    let eif_builder_process = eif_builder
+        .stdout(Stdio::piped())
         .spawn()?
-        .wait()
+        .wait_with_output()
         .await?;
-    if !eif_builder_process.success() {
+    debug!(stdout = %String::from_utf8_lossy(&eif_builder_process.stdout));
+    if !eif_builder_process.status.success() {
         return Err(failure::err_msg("Docker eif-builder error."));
-    } else {
-        let path_buf = cwd.join(eif_name);
-        info!("EIF written to {}", path_buf.display());
     }
-    Ok(eif_builder_process)
+    let path_buf = cwd.join(eif_name);
+    info!("EIF written to {}", path_buf.display());
+    let built_arch = Eif::read(&path_buf)?.arch;
+    if built_arch != arch {
+        return Err(failure::err_msg(format!(
+            "eif-builder produced an {} EIF instead of {}.",
+            built_arch, arch
+        )));
+    }
+
+    let measurements = parse_measurements(&eif_builder_process.stdout)?;
+    if signing.is_some() && measurements.pcr8.is_none() {
+        return Err(failure::err_msg(
+            "Signed EIF was built without a PCR8 measurement.",
+        ));
+    }
+    let manifest = BuildManifest {
+        eif: path_buf.display().to_string(),
+        eif_sha384: state::sha384_file(&path_buf)?,
+        measurements,
+        arch,
+        context: fs::canonicalize(&dockerdir)?.display().to_string(),
+        dockerfile: fs::canonicalize(&dockerfile_path)?.display().to_string(),
+        docker_image_digest: image_digest("nitrogen-build", "{{.Id}}").await?,
+        builder_image: EIF_BUILDER_IMAGE.to_string(),
+        builder_image_digest: image_digest(
+            EIF_BUILDER_IMAGE,
+            "{{if .RepoDigests}}{{index .RepoDigests 0}}{{else}}{{.Id}}{{end}}",
+        )
+        .await?,
+        signing_certificate: signing,
+        built_at: DateTime::from(SystemTime::now()).fmt(Format::DateTime)?,
+    };
+    let manifest_path = manifest.save()?;
+    info!(
+        pcr0 = manifest.measurements.pcr0,
+        pcr1 = manifest.measurements.pcr1,
+        pcr2 = manifest.measurements.pcr2,
+        "Build manifest written to {}",
+        manifest_path.display()
+    );
+    if let Some(pcr8) = &manifest.measurements.pcr8 {
+        info!(pcr8, "EIF is signed.");
+    }
+
+    Ok(BuildOutput {
+        manifest,
+        manifest_path: manifest_path.display().to_string(),
+        elapsed_secs: started.elapsed().as_secs_f64(),
+    })
 }
@@ -1,6 +1,12 @@
-use crate::cf_utilities as utilities;
-use aws_sdk_cloudformation::{model::StackStatus, Client};
+use crate::cf_utilities::{
+    self as utilities, ResourceChange, StackEvents, StackOperation, StackProgress, StackWaiter,
+};
+use crate::instance_types::InstanceType;
+use crate::known_hosts;
+use crate::output::{DeleteOutput, PlanOutput};
+use aws_sdk_cloudformation::Client;
 use failure::Error;
+use std::time::Instant;
 use tracing::{info, instrument};
 
 async fn delete_stack(client: &Client, name: &String) -> Result<(), Error> {
@@ -9,36 +15,70 @@ async fn delete_stack(client: &Client, name: &String) -> Result<(), Error> {
 }
 
 #[instrument(level = "debug", skip(client))]
-pub async fn delete(client: &Client, name: &String) -> Result<(), Error> {
+pub async fn delete(
+    client: &Client,
+    name: &String,
+    waiter: &StackWaiter,
+) -> Result<DeleteOutput, Error> {
+    let started = Instant::now();
     let this_stack = utilities::get_stack(client, name).await?;
     let stack_id = this_stack.stack_id().unwrap_or_default();
 
+    let mut events = StackEvents::since_now(client, stack_id).await?;
     delete_stack(client, name).await?;
 
-    let (stack_status, stack_status_reason) = loop {
-        let (status, status_reason) = utilities::check_stack_status(client, stack_id).await?;
-        tokio::time::sleep(tokio::time::Duration::new(4, 0)).await;
-        if status != StackStatus::DeleteInProgress {
-            break (status, status_reason);
-        }
-    };
-    match stack_status {
-        StackStatus::DeleteComplete => {
-            info!("Successfully deleted stack '{}'.", name);
-        }
-        StackStatus::DeleteFailed => {
-            return Err(failure::err_msg(
-                "Received DeleteFailed status from CloudFormation stack, please check \
-                AWS console or AWS logs for more information.",
-            ))
-        }
-        other_status => {
-            return Err(failure::err_msg(format!(
-                "{:#?}: {}",
-                other_status, stack_status_reason
-            )))
-        }
+    let outcome = waiter
+        .wait(client, &mut events, StackOperation::Delete)
+        .await?;
+    if outcome.progress != StackProgress::Succeeded {
+        return Err(events.failure(StackOperation::Delete, &outcome));
     }
+    info!("Successfully deleted stack '{}'.", name);
+    known_hosts::remove(name)?;
 
-    Ok(())
+    Ok(DeleteOutput {
+        name: name.to_string(),
+        stack_id: stack_id.to_string(),
+        elapsed_secs: started.elapsed().as_secs_f64(),
+    })
+}
+
+/// List the resources `delete` would remove, without deleting anything.
+#[instrument(level = "debug", skip(client))]
+pub async fn delete_dry_run(client: &Client, name: &String) -> Result<PlanOutput, Error> {
+    let stack = utilities::get_stack(client, name).await?;
+    let resources = client
+        .describe_stack_resources()
+        .stack_name(name)
+        .send()
+        .await?;
+    let changes = resources
+        .stack_resources()
+        .unwrap_or_default()
+        .iter()
+        .map(|resource| ResourceChange {
+            action: "Remove".to_string(),
+            logical_resource_id: resource
+                .logical_resource_id()
+                .unwrap_or_default()
+                .to_string(),
+            resource_type: resource.resource_type().unwrap_or_default().to_string(),
+            replacement: None,
+            properties: vec![],
+        })
+        .collect();
+
+    let instance_type = utilities::stack_parameter(&stack, "InstanceType");
+    let disk_size = utilities::stack_parameter(&stack, "DiskSize")
+        .and_then(|size| size.parse().ok())
+        .unwrap_or_default();
+    Ok(PlanOutput {
+        name: name.to_string(),
+        action: "delete".to_string(),
+        changes,
+        instance_type: instance_type.map(str::to_string),
+        estimated_hourly_usd: instance_type
+            .and_then(|t| InstanceType::lookup(t).ok())
+            .map(|t| t.estimate_hourly_usd(disk_size)),
+    })
 }
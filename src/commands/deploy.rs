@@ -1,175 +1,101 @@
-use crate::cf_utilities as utilities;
+use crate::cf_utilities::{self as utilities, StackOutputs};
+use crate::egress::{self, Destination};
+use crate::eif::Eif;
+use crate::enclave::EnclaveDescription;
+use crate::instance_types::InstanceType;
+use crate::known_hosts;
+use crate::output::DeployOutput;
+use crate::remote::RemoteHost;
+use crate::sizing::{self, MemorySizing};
 use aws_sdk_cloudformation::Client;
 use failure::Error;
-use std::str;
-use std::{
-    fs,
-    process::{Command, Output},
-};
+use std::path::Path;
+use std::time::{Duration, Instant};
 use tracing::{debug, info, instrument};
 
-fn terminate_existing_enclaves(ssh_key: &str, url: &str) -> Result<(), Error> {
-    info!("Terminating any existing enclaves");
-    let terminate_out = Command::new("ssh")
-        .args([
-            "-i",
-            ssh_key,
-            format!("ec2-user@{}", url).as_str(),
-            "nitro-cli",
-            "terminate-enclave",
-            "--all",
-        ])
-        .output()?;
-
-    debug!(stdout=?terminate_out);
+/// How long to wait for a freshly created instance to accept SSH.
+const READY_TIMEOUT: Duration = Duration::from_secs(300);
 
-    if !terminate_out.status.success() {
-        Err(failure::err_msg(format!(
-            "failed to terminate any currently running enclaves {:?}",
-            terminate_out
-        )))
-    } else {
-        Ok(())
-    }
+fn terminate_existing_enclaves(remote: &RemoteHost) -> Result<(), Error> {
+    info!("Terminating any existing enclaves");
+    let terminate_out = remote.exec("nitro-cli terminate-enclave --all")?;
+    debug!(stdout = %String::from_utf8_lossy(&terminate_out.stdout));
+    Ok(())
 }
 
 fn update_allocator_memory_and_cpu_count(
     memory: u64,
     cpu_count: u64,
-    ssh_key: &str,
-    url: &str,
+    remote: &RemoteHost,
 ) -> Result<(), Error> {
     info!(
         memory,
-        cpu_count, "Updating enclave allocator memory (in MB) and CPU count."
+        cpu_count, "Updating enclave allocator memory (in MiB) and CPU count."
     );
-    let sed_out = Command::new("ssh")
-        .args([
-            "-i",
-            ssh_key,
-            format!("ec2-user@{}", url).as_str(),
-            "sudo",
-            "sed",
-            "-i",
-            format!("-e 's/memory_mib: .*/memory_mib: {}/g'", memory).as_str(),
-            format!("-e 's/cpu_count: .*/cpu_count: {}/g'", cpu_count).as_str(),
-            "/etc/nitro_enclaves/allocator.yaml",
-        ])
-        .output()?;
-
-    debug!(stdout=?sed_out);
-    if !sed_out.status.success() {
-        return Err(failure::err_msg(format!(
-            "failed to update allocator config with sed {:?}",
-            sed_out
-        )));
-    }
+    remote.exec(&format!(
+        "sudo sed -i -e 's/memory_mib: .*/memory_mib: {}/g' -e 's/cpu_count: .*/cpu_count: {}/g' \
+        /etc/nitro_enclaves/allocator.yaml",
+        memory, cpu_count
+    ))?;
 
     info!("Restarting enclave allocator service.");
-    let systemctl_out = Command::new("ssh")
-        .args([
-            "-i",
-            ssh_key,
-            format!("ec2-user@{}", url).as_str(),
-            "sudo",
-            "systemctl",
-            "restart",
-            "nitro-enclaves-allocator.service",
-        ])
-        .output()?;
-
-    debug!(std_out=?systemctl_out);
-    if !systemctl_out.status.success() {
-        Err(failure::err_msg(format!(
-            "failed to restart allocator after reconfig {:?}",
-            systemctl_out
-        )))
-    } else {
-        Ok(())
-    }
+    remote.exec("sudo systemctl restart nitro-enclaves-allocator.service")?;
+    Ok(())
 }
 
-fn deploy_eif(eif_path: &str, ssh_key: &str, url: &str) -> Result<(), Error> {
+fn deploy_eif(eif_path: &Path, remote_eif: &str, remote: &RemoteHost) -> Result<(), Error> {
     info!(
         "Deploying {} to the instance http://{} (this may take some time, especially for larger files)",
-        eif_path,
-        url
+        eif_path.display(),
+        remote.host()
     );
-    let scp_out = Command::new("scp")
-        .args([
-            "-i",
-            ssh_key,
-            eif_path,
-            format!("ec2-user@{}:~", url).as_str(),
-        ])
-        .output()?;
-    debug!(stdout=?scp_out);
-
-    if !scp_out.status.success() {
-        Err(failure::err_msg(format!(
-            "failed to copy eif to enclave host {:?}",
-            scp_out
-        )))
-    } else {
-        Ok(())
-    }
+    let mut reported = 0;
+    remote.upload(eif_path, remote_eif, |written, total| {
+        let percent = written * 100 / total.max(1);
+        if percent >= reported + 10 || written == total {
+            reported = percent;
+            info!(written, total, "Uploaded {}%", percent);
+        }
+    })
 }
 
 fn run_eif(
-    eif_path: &str,
+    remote_eif: &str,
     cpu_count: &u64,
     mem: &u64,
-    ssh_key: &str,
-    url: &str,
+    remote: &RemoteHost,
     debug: bool,
-) -> Result<Output, Error> {
+) -> Result<EnclaveDescription, Error> {
     info!("Running EIF in enclave.");
-    let args = [
-        "-i",
-        ssh_key,
-        &format!("ec2-user@{}", url),
-        "nitro-cli",
-        "run-enclave",
-        "--enclave-cid",
-        "16",
-        "--eif-path",
-        &format!("~/{}", eif_path),
-        "--cpu-count",
-        &cpu_count.to_string(),
-        "--memory",
-        &mem.to_string(),
-    ];
-    let run_out = match debug {
-        true => Command::new("ssh")
-            .args(args)
-            .arg("--debug-mode")
-            .output()?,
-        false => Command::new("ssh").args(args).output()?,
-    };
-    debug!(stdout=?run_out);
-
-    info!(public_dns = url, "EIF is now running");
-
-    if !run_out.status.success() {
-        return Err(failure::err_msg(format!(
-            "failed to run enclave{:?}",
-            run_out
-        )));
+    let mut command = format!(
+        "nitro-cli run-enclave --enclave-cid 16 --eif-path {} --cpu-count {} --memory {}",
+        remote_eif, cpu_count, mem
+    );
+    if debug {
+        command.push_str(" --debug-mode");
     }
-
-    match utilities::check_enclave_status(ssh_key, url) {
-        Ok(()) => info!("Enclave up and running!"),
-        Err(err) => {
-            return Err(failure::err_msg(format!(
-                "Error: something went wrong with deployment. {}",
-                err
-            )))
+    let run_out = remote.exec(&command)?;
+    debug!(stdout = %String::from_utf8_lossy(&run_out.stdout));
+
+    info!(public_dns = remote.host(), "EIF is now running");
+
+    match utilities::check_enclave_status(remote) {
+        Ok(enclave) => {
+            info!(
+                enclave_id = enclave.enclave_id,
+                enclave_cid = enclave.enclave_cid,
+                "Enclave up and running!"
+            );
+            Ok(enclave)
         }
+        Err(err) => Err(failure::err_msg(format!(
+            "Error: something went wrong with deployment. {}",
+            err
+        ))),
     }
-
-    Ok(run_out)
 }
 
+#[allow(clippy::too_many_arguments)]
 #[instrument(level = "debug")]
 pub async fn deploy(
     client: &Client,
@@ -178,23 +104,81 @@ pub async fn deploy(
     ssh_key: &String,
     cpu_count: u64,
     memory: Option<u64>,
+    memory_headroom: u64,
     debug_mode: bool,
-) -> Result<Output, Error> {
-    let this_stack = utilities::get_stack(client, stack_name).await?;
-    let url = utilities::get_instance_url(&this_stack).await?;
-
-    // If enclave memory not specified, default to 5x eif size
-    let metadata = fs::metadata(eif)?;
-    let eif_size = metadata.len() / 1000000; // to mb
-    let mem = if memory.is_none() {
-        eif_size * 5
-    } else {
-        memory.unwrap()
+    egress: &[Destination],
+) -> Result<DeployOutput, Error> {
+    let started = Instant::now();
+    let stack = utilities::get_stack(client, stack_name).await?;
+    let outputs = StackOutputs::from_stack(&stack)?;
+    let instance_type = match utilities::stack_parameter(&stack, "InstanceType") {
+        Some(instance_type) => InstanceType::lookup_or_warn(instance_type)?,
+        None => {
+            return Err(failure::err_msg(format!(
+                "Stack '{}' has no InstanceType parameter.",
+                stack_name
+            )))
+        }
+    };
+    let known_hosts = known_hosts::for_stack(stack_name, &outputs)?;
+    let url = outputs.host().to_string();
+
+    // The EIF lands in the home directory of the remote user
+    let eif_path = Path::new(eif);
+    let image = Eif::read(eif_path)?;
+    image.validate()?;
+    if let Some(instance_type) = &instance_type {
+        instance_type.check_arch(image.arch)?;
+    }
+    info!(
+        pcr0 = image.measurements.pcr0,
+        signed = image.signed,
+        "Validated EIF {}.",
+        eif
+    );
+    let mut memory = MemorySizing::for_eif(&image, memory_headroom, memory)?;
+    if let Some(instance_type) = &instance_type {
+        memory.check_instance_type(instance_type, cpu_count)?;
+    }
+    let remote_eif = match eif_path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => return Err(failure::err_msg(format!("{} is not a file", eif))),
     };
 
     info!("Using instance URL {}...", url);
-    terminate_existing_enclaves(ssh_key, &url)?;
-    update_allocator_memory_and_cpu_count(mem, cpu_count, ssh_key, &url)?;
-    deploy_eif(eif, ssh_key, &url)?;
-    run_eif(eif, &cpu_count, &mem, ssh_key, &url, debug_mode)
+    // Retrying sleeps between attempts, which must not stall the runtime
+    let remote = {
+        let (url, ssh_key) = (url.clone(), ssh_key.to_string());
+        tokio::task::spawn_blocking(move || {
+            RemoteHost::connect_when_ready(&url, &ssh_key, &known_hosts, READY_TIMEOUT)
+        })
+        .await??
+    };
+    memory.check_instance(sizing::instance_memory_mib(&remote)?)?;
+    info!(
+        memory_mib = memory.memory_mib,
+        "Sizing enclave memory: {}", memory
+    );
+    terminate_existing_enclaves(&remote)?;
+    update_allocator_memory_and_cpu_count(memory.memory_mib, cpu_count, &remote)?;
+    deploy_eif(eif_path, &remote_eif, &remote)?;
+    let egress = egress::configure(&remote, egress)?;
+    let enclave = run_eif(
+        &remote_eif,
+        &cpu_count,
+        &memory.memory_mib,
+        &remote,
+        debug_mode,
+    )?;
+
+    Ok(DeployOutput {
+        name: stack_name.to_string(),
+        host: url,
+        public_dns: outputs.public_dns,
+        eif: eif.to_string(),
+        enclave,
+        memory,
+        egress,
+        elapsed_secs: started.elapsed().as_secs_f64(),
+    })
 }
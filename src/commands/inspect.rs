@@ -0,0 +1,25 @@
+use crate::eif::Eif;
+use crate::instance_types::InstanceType;
+use crate::output::InspectOutput;
+use crate::sizing::MemorySizing;
+use failure::Error;
+use std::path::Path;
+use tracing::{instrument, warn};
+
+/// Read an EIF and compute its measurements locally, without Docker.
+#[instrument(level = "debug")]
+pub fn inspect(eif: &str, cpu_count: u64, memory_headroom: u64) -> Result<InspectOutput, Error> {
+    let image = Eif::read(Path::new(eif))?;
+    // Still report what could be read, so a broken image can be looked into
+    if let Err(err) = image.validate() {
+        warn!("{}", err);
+    }
+    let memory = MemorySizing::for_eif(&image, memory_headroom, None)?;
+    let suggested_instance_type =
+        InstanceType::smallest_for(image.arch, cpu_count, memory.memory_mib);
+    Ok(InspectOutput {
+        image,
+        memory,
+        suggested_instance_type,
+    })
+}
@@ -0,0 +1,112 @@
+use crate::cf_utilities::{self as utilities, StackOutputs};
+use crate::enclave::EnclaveDescription;
+use crate::known_hosts;
+use crate::remote::RemoteHost;
+use crate::state::StackState;
+use aws_sdk_cloudformation::{model::Stack, Client};
+use aws_smithy_types::date_time::Format;
+use failure::Error;
+use serde::Serialize;
+use tracing::{debug, instrument};
+
+/// What is running on a stack's instance, as far as we could find out.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum EnclaveSummary {
+    Running(Box<EnclaveDescription>),
+    NotRunning,
+    Unknown { reason: String },
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StackSummary {
+    pub name: String,
+    pub status: String,
+    pub instance_type: Option<String>,
+    pub public_dns: Option<String>,
+    pub created: Option<String>,
+    pub enclave: EnclaveSummary,
+}
+
+fn summarize_enclave(stack_name: &str, outputs: Option<&StackOutputs>) -> EnclaveSummary {
+    let unknown = |reason: String| EnclaveSummary::Unknown { reason };
+    let outputs = match outputs {
+        Some(outputs) => outputs,
+        None => return unknown("stack has no outputs".to_string()),
+    };
+    let private_key = match StackState::load(stack_name) {
+        Ok(Some(StackState {
+            private_key: Some(private_key),
+            ..
+        })) => private_key,
+        Ok(_) => return unknown("no SSH private key recorded".to_string()),
+        Err(err) => return unknown(err.to_string()),
+    };
+
+    let enclaves = known_hosts::for_stack(stack_name, outputs).and_then(|known_hosts| {
+        let remote = RemoteHost::connect(outputs.host(), &private_key, &known_hosts)?;
+        utilities::describe_enclaves(&remote)
+    });
+    match enclaves {
+        Ok(enclaves) => match enclaves.into_iter().next() {
+            Some(enclave) => EnclaveSummary::Running(Box::new(enclave)),
+            None => EnclaveSummary::NotRunning,
+        },
+        Err(err) => unknown(err.to_string()),
+    }
+}
+
+async fn summarize(stack: Stack) -> Result<StackSummary, Error> {
+    let name = stack.stack_name().unwrap_or_default().to_string();
+    let outputs = StackOutputs::from_stack(&stack).ok();
+    let public_dns = outputs.as_ref().and_then(|o| o.public_dns.clone());
+
+    // Describing the enclave needs a blocking SSH session per stack
+    let enclave_stack = name.clone();
+    let enclave =
+        tokio::task::spawn_blocking(move || summarize_enclave(&enclave_stack, outputs.as_ref()))
+            .await?;
+
+    Ok(StackSummary {
+        status: stack
+            .stack_status()
+            .map(|s| s.as_str().to_string())
+            .unwrap_or_default(),
+        instance_type: utilities::stack_parameter(&stack, "InstanceType").map(str::to_string),
+        public_dns,
+        created: stack
+            .creation_time()
+            .and_then(|t| t.fmt(Format::DateTime).ok()),
+        enclave,
+        name,
+    })
+}
+
+/// Summaries of every stack in the client's region that was created by nitrogen,
+/// either recognised by its tag or recorded in the local state.
+#[instrument(level = "debug", skip(client))]
+pub async fn list(client: &Client) -> Result<Vec<StackSummary>, Error> {
+    let registered: Vec<String> = StackState::list()?.into_iter().map(|s| s.name).collect();
+    let stacks: Vec<Stack> = utilities::describe_all_stacks(client)
+        .await?
+        .into_iter()
+        .filter(|stack| {
+            utilities::is_managed(stack)
+                || registered
+                    .iter()
+                    .any(|name| Some(name.as_str()) == stack.stack_name())
+        })
+        .collect();
+    debug!(count = stacks.len(), "Found nitrogen stacks.");
+
+    let handles: Vec<_> = stacks
+        .into_iter()
+        .map(|stack| tokio::spawn(summarize(stack)))
+        .collect();
+    let mut summaries = vec![];
+    for handle in handles {
+        summaries.push(handle.await??);
+    }
+    summaries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(summaries)
+}
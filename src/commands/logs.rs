@@ -1,48 +1,51 @@
 use crate::cf_utilities as utilities;
+use crate::known_hosts;
+use crate::output::{LogsOutput, OutputFormat};
+use crate::remote::RemoteHost;
 use aws_sdk_cloudformation::Client;
 use failure::Error;
-use serde_json::json;
-use std::process::{Command, Stdio};
-use std::str;
+use std::io::{self, Write};
 use tracing::{error, info, instrument};
 
 #[instrument(level = "debug")]
-pub async fn logs(client: &Client, stack_name: &str, ssh_key: &str) -> Result<(), Error> {
-    let this_stack = utilities::get_stack(client, stack_name).await?;
-    let url = utilities::get_instance_url(&this_stack).await?;
+pub async fn logs(
+    client: &Client,
+    stack_name: &str,
+    ssh_key: &str,
+    output: OutputFormat,
+) -> Result<(), Error> {
+    let outputs = utilities::get_stack_outputs(client, stack_name).await?;
+    let known_hosts = known_hosts::for_stack(stack_name, &outputs)?;
+    let url = outputs.host().to_string();
 
-    let enclave = utilities::describe_enclave(ssh_key, &url)?;
-    let enclave_name = match enclave.get("EnclaveName") {
-        Some(name) => name,
-        None => return Err(failure::err_msg("Enclave has no name.")),
-    };
+    let remote = RemoteHost::connect(&url, ssh_key, &known_hosts)?;
+    let enclave = utilities::describe_enclave(&remote)?;
+    if enclave.enclave_name.is_empty() {
+        return Err(failure::err_msg("Enclave has no name."));
+    }
 
-    if enclave.get("Flags") != Some(&json!("DEBUG_MODE")) {
+    if !enclave.is_debug_mode() {
         error!("Enclave is not in debug mode. Please redeploy with \"--debug-mode\" flag.");
         return Ok(());
     }
 
-    info!("Getting logs from enclave console: {}", url);
-    let console_out = Command::new("ssh")
-        .args([
-            "-i",
-            ssh_key,
-            &format!("ec2-user@{}", url),
-            "nitro-cli",
-            "console",
-            "--enclave-name",
-            &enclave_name.to_string(),
-        ])
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .output()?;
+    let enclave_name = enclave.enclave_name.clone();
+    output.emit(&LogsOutput {
+        name: stack_name.to_string(),
+        host: url.clone(),
+        enclave,
+    })?;
 
-    if !console_out.status.success() {
-        return Err(failure::err_msg(format!(
-            "failed to get enclave console{:?}",
-            console_out
-        )));
-    }
+    // Keep stdout reserved for the metadata document in JSON mode
+    let mut console_stdout: Box<dyn Write> = match output {
+        OutputFormat::Json => Box::new(io::stderr()),
+        OutputFormat::Text => Box::new(io::stdout()),
+    };
 
-    Ok(())
+    info!("Getting logs from enclave console: {}", url);
+    remote.exec_streamed(
+        &format!("nitro-cli console --enclave-name {}", enclave_name),
+        &mut console_stdout,
+        &mut io::stderr(),
+    )
 }
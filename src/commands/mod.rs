@@ -1,10 +1,24 @@
+pub mod attest;
 pub mod build;
 pub mod delete;
 pub mod deploy;
+pub mod inspect;
+pub mod list;
 pub mod logs;
+pub mod pin_host_key;
 pub mod setup;
+pub mod ssh_allow;
+pub mod update;
+pub mod verify_build;
+pub use self::attest::attest;
 pub use self::build::build;
 pub use self::delete::delete;
 pub use self::deploy::deploy;
+pub use self::inspect::inspect;
+pub use self::list::list;
 pub use self::logs::logs;
+pub use self::pin_host_key::pin_host_key;
 pub use self::setup::setup;
+pub use self::ssh_allow::ssh_allow;
+pub use self::update::update;
+pub use self::verify_build::verify_build;
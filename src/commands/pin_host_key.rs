@@ -0,0 +1,42 @@
+use crate::cf_utilities as utilities;
+use crate::known_hosts;
+use crate::output::PinHostKeyOutput;
+use aws_sdk_cloudformation::Client;
+use failure::Error;
+use std::fs;
+use std::path::Path;
+use tracing::{info, instrument};
+
+/// Pin a host key obtained out of band, such as from the instance's console
+/// output, for a stack created before nitrogen pinned host keys itself.
+/// `host_key` is a public key line or a file holding one.
+#[instrument(level = "debug", skip(client))]
+pub async fn pin_host_key(
+    client: &Client,
+    stack_name: &str,
+    host_key: &str,
+) -> Result<PinHostKeyOutput, Error> {
+    let outputs = utilities::get_stack_outputs(client, stack_name).await?;
+    if outputs.host_key.is_some() {
+        return Err(failure::err_msg(format!(
+            "Stack '{}' publishes its SSH host key, which nitrogen pins by itself.",
+            stack_name
+        )));
+    }
+    let host_key = match Path::new(host_key).is_file() {
+        true => fs::read_to_string(host_key)?,
+        false => host_key.to_string(),
+    };
+    let addresses = outputs.addresses();
+    let known_hosts = known_hosts::pin(stack_name, &addresses, host_key.trim())?;
+    info!(
+        "Pinned the SSH host key of '{}' in {}.",
+        stack_name,
+        known_hosts.display()
+    );
+    Ok(PinHostKeyOutput {
+        name: stack_name.to_string(),
+        known_hosts: known_hosts.display().to_string(),
+        addresses: addresses.into_iter().map(str::to_string).collect(),
+    })
+}
@@ -1,12 +1,59 @@
-use crate::cf_utilities as utilities;
+use crate::arch::Arch;
+use crate::cf_utilities::{
+    self as utilities, StackEvents, StackOperation, StackProgress, StackWaiter,
+};
+use crate::commands::delete;
+use crate::ingress::{self, Source};
+use crate::instance_types::InstanceType;
+use crate::known_hosts;
+use crate::output::{PlanOutput, SetupOutput};
+use crate::ports::{self, PortMapping};
 use aws_sdk_cloudformation::{
-    model::{Parameter, StackStatus},
+    model::{self, ChangeSetType, Parameter, StackStatus, Tag},
     output::CreateStackOutput,
     Client,
 };
+use clap::ValueEnum;
 use failure::Error;
 use std::fs;
-use tracing::{info, instrument};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::time::Instant;
+use tracing::{info, instrument, warn};
+
+/// Where the instance is launched. Without a VPC and subnet it goes into the
+/// default VPC of the region.
+#[derive(Clone, Debug, Default)]
+pub struct Network {
+    pub vpc_id: Option<String>,
+    pub subnet_id: Option<String>,
+    /// Launch without a public IP, reachable only from inside the VPC.
+    pub private: bool,
+}
+
+/// What CloudFormation does with a stack whose creation fails.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OnFailure {
+    /// Delete the stack and everything it created
+    #[value(name = "DELETE")]
+    Delete,
+    /// Roll back, leaving the stack in ROLLBACK_COMPLETE for inspection
+    #[default]
+    #[value(name = "ROLLBACK")]
+    Rollback,
+    /// Keep the resources that were created, for debugging
+    #[value(name = "DO_NOTHING")]
+    DoNothing,
+}
+
+impl From<OnFailure> for model::OnFailure {
+    fn from(on_failure: OnFailure) -> Self {
+        match on_failure {
+            OnFailure::Delete => model::OnFailure::Delete,
+            OnFailure::Rollback => model::OnFailure::Rollback,
+            OnFailure::DoNothing => model::OnFailure::DoNothing,
+        }
+    }
+}
 
 fn lift_to_param(key: impl Into<String>, value: impl Into<String>) -> Parameter {
     Parameter::builder()
@@ -15,6 +62,38 @@ fn lift_to_param(key: impl Into<String>, value: impl Into<String>) -> Parameter
         .build()
 }
 
+#[allow(clippy::too_many_arguments)]
+fn stack_parameters(
+    name: &String,
+    instance_type: &String,
+    disk_size: &usize,
+    ports: &[PortMapping],
+    service_locations: &[Source],
+    public_key: &String,
+    ssh_location: &String,
+    arch: Arch,
+    network: &Network,
+) -> Vec<Parameter> {
+    vec![
+        lift_to_param("InstanceName", name),
+        lift_to_param("InstanceType", instance_type),
+        lift_to_param("DiskSize", disk_size.to_string()),
+        lift_to_param("Port", ports[0].host.to_string()),
+        lift_to_param("VsockPort", ports[0].vsock.to_string()),
+        lift_to_param("ExtraPortMappings", ports::extra_mappings(ports)),
+        lift_to_param(
+            "ServiceLocations",
+            ingress::service_locations(service_locations),
+        ),
+        lift_to_param("PublicKey", public_key),
+        lift_to_param("SSHLocation", ssh_location),
+        lift_to_param("LatestAmiId", arch.ami_parameter()),
+        lift_to_param("VpcId", network.vpc_id.as_deref().unwrap_or_default()),
+        lift_to_param("SubnetId", network.subnet_id.as_deref().unwrap_or_default()),
+        lift_to_param("AssociatePublicIp", (!network.private).to_string()),
+    ]
+}
+
 #[allow(clippy::too_many_arguments)]
 async fn setup_stack(
     client: &Client,
@@ -22,37 +101,130 @@ async fn setup_stack(
     name: &String,
     instance_type: &String,
     disk_size: &usize,
-    port: &usize,
+    ports: &[PortMapping],
+    service_locations: &[Source],
     public_key: &String,
     ssh_location: &String,
+    arch: Arch,
+    network: &Network,
+    on_failure: OnFailure,
 ) -> Result<CreateStackOutput, Error> {
     let stack = client
         .create_stack()
         .stack_name(name)
         .template_body(setup_template)
-        .parameters(lift_to_param("InstanceName", name))
-        .parameters(lift_to_param("InstanceType", instance_type))
-        .parameters(lift_to_param("DiskSize", disk_size.to_string()))
-        .parameters(lift_to_param("Port", port.to_string()))
-        .parameters(lift_to_param("PublicKey", public_key))
-        .parameters(lift_to_param("SSHLocation", ssh_location));
+        .on_failure(on_failure.into())
+        .set_parameters(Some(stack_parameters(
+            name,
+            instance_type,
+            disk_size,
+            ports,
+            service_locations,
+            public_key,
+            ssh_location,
+            arch,
+            network,
+        )))
+        .tags(
+            Tag::builder()
+                .key(utilities::MANAGED_TAG)
+                .value(env!("CARGO_PKG_VERSION"))
+                .build(),
+        );
     let stack_output = stack.send().await?;
     Ok(stack_output)
 }
 
+/// Status of a stack called `name` left behind by a failed setup, which
+/// blocks creating a new one with the same name.
+pub async fn failed_stack(client: &Client, name: &str) -> Result<Option<StackStatus>, Error> {
+    let status = match utilities::find_stack(client, name).await? {
+        Some(stack) => stack.stack_status().cloned(),
+        None => return Ok(None),
+    };
+    Ok(status.filter(|status| {
+        matches!(
+            status,
+            StackStatus::CreateFailed
+                | StackStatus::RollbackComplete
+                | StackStatus::RollbackFailed
+                | StackStatus::DeleteFailed
+        )
+    }))
+}
+
+fn confirm(prompt: &str) -> Result<bool, Error> {
+    // On stderr, as stdout may be the JSON output
+    eprint!("{} [y/N] ", prompt);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Delete a stack left behind by a failed setup, so that the name can be
+/// reused. Asks first unless `delete_failed` is set.
+async fn clear_failed_stack(
+    client: &Client,
+    name: &String,
+    delete_failed: bool,
+    waiter: &StackWaiter,
+) -> Result<(), Error> {
+    let status = match failed_stack(client, name).await? {
+        Some(status) => status,
+        None => return Ok(()),
+    };
+    let prompt = format!(
+        "Stack '{}' is left over from a failed setup ({}). Delete it?",
+        name,
+        status.as_str()
+    );
+    let delete_it = delete_failed || (io::stdin().is_terminal() && confirm(&prompt)?);
+    if !delete_it {
+        return Err(failure::err_msg(format!(
+            "Stack '{}' is left over from a failed setup ({}). Inspect it in the \
+            CloudFormation console, then run `nitrogen delete {}` or pass --delete-failed.",
+            name,
+            status.as_str(),
+            name
+        )));
+    }
+    warn!(
+        status = status.as_str(),
+        "Deleting failed stack '{}'.", name
+    );
+    delete(client, name, waiter).await?;
+    Ok(())
+}
+
 #[instrument(level = "debug", skip(client, setup_template))]
 #[allow(clippy::too_many_arguments)]
 pub async fn setup(
     client: &Client,
-    setup_template: &String,
+    setup_template: &str,
     name: &String,
     instance_type: &String,
     disk_size: &usize,
-    port: &usize,
+    ports: &[PortMapping],
+    service_locations: &[Source],
     public_key_file: &String,
     ssh_location: &String,
-) -> Result<Vec<(String, String)>, Error> {
+    arch: Arch,
+    network: &Network,
+    on_failure: OnFailure,
+    delete_failed: bool,
+    waiter: &StackWaiter,
+) -> Result<SetupOutput, Error> {
+    let started = Instant::now();
+    // Catch unsupported types before CloudFormation does, minutes into the stack
+    if let Some(instance) = InstanceType::lookup_or_warn(instance_type)? {
+        instance.check_arch(arch)?;
+    }
+    ports::check(ports)?;
+    ingress::warn_if_public(service_locations);
+    let setup_template = &ingress::render(setup_template, ports, service_locations)?;
     let public_key = fs::read_to_string(public_key_file)?;
+    clear_failed_stack(client, name, delete_failed, waiter).await?;
 
     let stack_output = setup_stack(
         client,
@@ -60,9 +232,13 @@ pub async fn setup(
         name,
         instance_type,
         disk_size,
-        port,
+        ports,
+        service_locations,
         &public_key,
         ssh_location,
+        arch,
+        network,
+        on_failure,
     )
     .await?;
     let stack_id = match stack_output.stack_id() {
@@ -74,43 +250,96 @@ pub async fn setup(
             ))
         }
     };
-    let (stack_status, stack_status_reason) = loop {
-        let (status, status_reason) = utilities::check_stack_status(client, stack_id).await?;
-        tokio::time::sleep(tokio::time::Duration::new(4, 0)).await;
-        if status != StackStatus::CreateInProgress {
-            break (status, status_reason);
-        }
-    };
-    match stack_status {
-        StackStatus::CreateComplete => {
-            let stack_id = stack_output.stack_id().unwrap();
-            info!(stack_id, "Successfully created enclave instance.");
-        }
-        StackStatus::CreateFailed => {
-            return Err(failure::err_msg(
-                "Received CreateFailed status from CloudFormation stack, please check \
-                AWS console or AWS logs for more information.",
-            ))
-        }
-        other_status => {
-            return Err(failure::err_msg(format!(
-                "{:#?}: {}",
-                other_status, stack_status_reason
-            )))
-        }
+    let mut events = StackEvents::new(stack_id);
+    let outcome = waiter
+        .wait(client, &mut events, StackOperation::Create)
+        .await?;
+    if outcome.progress != StackProgress::Succeeded {
+        return Err(events.failure(StackOperation::Create, &outcome));
     }
-    // Stack was created successfully, report outputs to stdout
-    let this_stack = utilities::get_stack(client, stack_id).await?;
-    // TODO handle missing outputs in this unwrap, maybe w/ warning instead of error?
-    let outputs: Vec<(String, String)> = this_stack
-        .outputs()
-        .unwrap()
-        .iter()
-        .map(|o| {
-            let k = o.output_key().unwrap().to_string();
-            let v = o.output_value().unwrap().to_string();
-            (k, v)
-        })
-        .collect();
-    Ok(outputs)
+    info!(stack_id, "Successfully created enclave instance.");
+    // Stack was created successfully, collect outputs for reporting
+    let outputs = utilities::get_stack_outputs(client, stack_id).await?;
+    let known_hosts = known_hosts::for_stack(name, &outputs)?;
+    info!(known_hosts = %known_hosts.display(), "Pinned SSH host key of enclave instance.");
+    Ok(SetupOutput {
+        name: name.to_string(),
+        outputs,
+        open_ports: std::iter::once(22)
+            .chain(ports.iter().map(|mapping| mapping.host as usize))
+            .collect(),
+        elapsed_secs: started.elapsed().as_secs_f64(),
+    })
+}
+
+/// Validate the template and parameters and list what `setup` would create,
+/// without creating anything. CloudFormation needs a stack to hold the change
+/// set, so an empty one in REVIEW_IN_PROGRESS is created and removed again.
+#[instrument(level = "debug", skip(client, setup_template))]
+#[allow(clippy::too_many_arguments)]
+pub async fn setup_dry_run(
+    client: &Client,
+    setup_template: &str,
+    name: &String,
+    instance_type: &String,
+    disk_size: &usize,
+    ports: &[PortMapping],
+    service_locations: &[Source],
+    public_key_file: &String,
+    ssh_location: &String,
+    arch: Arch,
+    network: &Network,
+    waiter: &StackWaiter,
+) -> Result<PlanOutput, Error> {
+    let instance = InstanceType::lookup_or_warn(instance_type)?;
+    if let Some(instance) = &instance {
+        instance.check_arch(arch)?;
+    }
+    ports::check(ports)?;
+    ingress::warn_if_public(service_locations);
+    let setup_template = &ingress::render(setup_template, ports, service_locations)?;
+    let public_key = fs::read_to_string(public_key_file)?;
+
+    client
+        .validate_template()
+        .template_body(setup_template)
+        .send()
+        .await?;
+    info!("Template is valid.");
+
+    let change_set = client
+        .create_change_set()
+        .stack_name(name)
+        .change_set_name("nitrogen-dry-run")
+        .change_set_type(ChangeSetType::Create)
+        .template_body(setup_template)
+        .set_parameters(Some(stack_parameters(
+            name,
+            instance_type,
+            disk_size,
+            ports,
+            service_locations,
+            &public_key,
+            ssh_location,
+            arch,
+            network,
+        )))
+        .send()
+        .await?;
+    let change_set_id = change_set.id().unwrap_or("nitrogen-dry-run").to_string();
+    let changes = utilities::describe_change_set(client, name, &change_set_id, waiter).await;
+
+    // Only ever remove the placeholder stack the change set created
+    let (status, _) = utilities::check_stack_status(client, name).await?;
+    if status == StackStatus::ReviewInProgress {
+        client.delete_stack().stack_name(name).send().await?;
+    }
+
+    Ok(PlanOutput {
+        name: name.to_string(),
+        action: "create".to_string(),
+        changes: changes?.unwrap_or_default(),
+        instance_type: Some(instance_type.to_string()),
+        estimated_hourly_usd: instance.map(|instance| instance.estimate_hourly_usd(*disk_size)),
+    })
 }
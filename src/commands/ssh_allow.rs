@@ -0,0 +1,30 @@
+use crate::cf_utilities::StackWaiter;
+use crate::commands::update;
+use crate::output::UpdateOutput;
+use aws_sdk_cloudformation::Client;
+use failure::Error;
+use tracing::{info, instrument};
+
+/// Change the CIDR range allowed to SSH into a stack's instance. Ingress rules
+/// are updated in place, so the instance is kept.
+#[instrument(level = "debug", skip(client))]
+pub async fn ssh_allow(
+    client: &Client,
+    stack_name: &str,
+    ssh_location: &str,
+    waiter: &StackWaiter,
+) -> Result<UpdateOutput, Error> {
+    info!("Allowing SSH into '{}' from {}.", stack_name, ssh_location);
+    update(
+        client,
+        stack_name,
+        None,
+        None,
+        None,
+        Some(ssh_location.to_string()),
+        vec![],
+        false,
+        waiter,
+    )
+    .await
+}
@@ -0,0 +1,493 @@
+use crate::cf_utilities::{
+    self as utilities, ResourceChange, StackEvents, StackOperation, StackProgress, StackWaiter,
+};
+use crate::ingress::{self, Source};
+use crate::instance_types::InstanceType;
+use crate::known_hosts;
+use crate::output::UpdateOutput;
+use crate::ports::{self, PortMapping};
+use aws_sdk_cloudformation::{
+    model::{ChangeSetType, Parameter, Stack},
+    Client,
+};
+use failure::Error;
+use serde_json::Value;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, instrument, warn};
+
+fn parameter(key: &str, value: Option<String>) -> Parameter {
+    let builder = Parameter::builder().parameter_key(key);
+    match value {
+        Some(value) => builder.parameter_value(value),
+        None => builder.use_previous_value(true),
+    }
+    .build()
+}
+
+async fn stack_template(client: &Client, stack: &Stack) -> Result<String, Error> {
+    let template = client
+        .get_template()
+        .stack_name(stack.stack_id().unwrap_or_default())
+        .send()
+        .await?;
+    Ok(template.template_body().unwrap_or_default().to_string())
+}
+
+async fn create_change_set(
+    client: &Client,
+    stack_name: &str,
+    change_set_name: &str,
+    template: Option<&str>,
+    parameters: &[Parameter],
+) -> Result<String, Error> {
+    let request = client
+        .create_change_set()
+        .stack_name(stack_name)
+        .change_set_name(change_set_name)
+        .change_set_type(ChangeSetType::Update)
+        .set_parameters(Some(parameters.to_vec()));
+    let request = match template {
+        Some(template) => request.template_body(template),
+        None => request.use_previous_template(true),
+    };
+    let change_set = request.send().await?;
+    info!(change_set = change_set_name, "Created change set.");
+    Ok(change_set.id().unwrap_or(change_set_name).to_string())
+}
+
+async fn delete_change_set(
+    client: &Client,
+    stack_name: &str,
+    change_set_id: &str,
+) -> Result<(), Error> {
+    client
+        .delete_change_set()
+        .stack_name(stack_name)
+        .change_set_name(change_set_id)
+        .send()
+        .await?;
+    Ok(())
+}
+
+fn replaces_instance(change: &ResourceChange) -> bool {
+    change.resource_type == "AWS::EC2::Instance" && change.is_replaced()
+}
+
+/// Rename every `field` of `value` equal to `from`, and every string value
+/// equal to it, such as a `Ref` or the first item of a `Fn::GetAtt`.
+fn rename(value: &mut Value, from: &str, to: &str) {
+    match value {
+        Value::String(s) if s == from => *s = to.to_string(),
+        Value::Array(items) => items.iter_mut().for_each(|item| rename(item, from, to)),
+        Value::Object(fields) => {
+            if let Some(field) = fields.remove(from) {
+                fields.insert(to.to_string(), field);
+            }
+            fields
+                .values_mut()
+                .for_each(|field| rename(field, from, to));
+        }
+        _ => {}
+    }
+}
+
+/// `template` with its host key wait condition and handle given new logical
+/// ids, so CloudFormation creates fresh ones for a replacement instance.
+fn renew_host_key(template: &str, suffix: u64) -> Result<String, Error> {
+    let mut template: Value = serde_json::from_str(template)?;
+    for prefix in ["HostKeyWaitHandle", "HostKeyWaitCondition"] {
+        let current = template["Resources"]
+            .as_object()
+            .and_then(|resources| resources.keys().find(|id| id.starts_with(prefix)))
+            .cloned()
+            .ok_or_else(|| {
+                failure::err_msg(format!(
+                    "The stack's template has no {} to publish a replacement instance's host \
+                    key through, so the instance cannot be replaced.",
+                    prefix
+                ))
+            })?;
+        rename(&mut template, &current, &format!("{}{}", prefix, suffix));
+    }
+    Ok(serde_json::to_string(&template)?)
+}
+
+/// The stack's template with its service ingress rules regenerated for
+/// `service_locations`, as the number of rules depends on them.
+async fn service_template(
+    client: &Client,
+    stack: &Stack,
+    service_locations: &[Source],
+) -> Result<String, Error> {
+    let parameter = |key: &str| utilities::stack_parameter(stack, key);
+    let (port, vsock_port, extra_mappings) = match (
+        parameter("Port"),
+        parameter("VsockPort"),
+        parameter("ExtraPortMappings"),
+        parameter("ServiceLocations"),
+    ) {
+        (Some(port), Some(vsock_port), Some(extra_mappings), Some(_)) => {
+            (port, vsock_port, extra_mappings)
+        }
+        _ => {
+            return Err(failure::err_msg(format!(
+                "Stack '{}' predates --service-location, recreate it with `nitrogen setup` \
+                to restrict its service ports.",
+                stack.stack_name().unwrap_or_default()
+            )))
+        }
+    };
+    let mappings = ports::from_parameters(port, vsock_port, extra_mappings)?;
+    ingress::render(
+        &stack_template(client, stack).await?,
+        &mappings,
+        service_locations,
+    )
+}
+
+/// Check `port` can replace the stack's primary port without clashing with
+/// SSH or its other mappings.
+fn check_port(stack: &Stack, port: u16) -> Result<(), Error> {
+    let parameter = |key: &str| utilities::stack_parameter(stack, key);
+    let mut mappings = match (
+        parameter("Port"),
+        parameter("VsockPort"),
+        parameter("ExtraPortMappings"),
+    ) {
+        (Some(port), Some(vsock_port), Some(extra_mappings)) => {
+            ports::from_parameters(port, vsock_port, extra_mappings)?
+        }
+        // Stacks from before port mappings only forward `Port`
+        _ => vec![PortMapping {
+            host: port,
+            vsock: ports::DEFAULT_VSOCK_PORT,
+        }],
+    };
+    mappings[0].host = port;
+    ports::check(&mappings)
+}
+
+fn log_change(change: &ResourceChange) {
+    let properties = change.properties.join(", ");
+    if change.is_replaced() {
+        warn!(
+            action = change.action,
+            resource_type = change.resource_type,
+            properties,
+            "{} will be replaced ({}).",
+            change.logical_resource_id,
+            change.replacement.as_deref().unwrap_or_default()
+        );
+    } else {
+        info!(
+            action = change.action,
+            resource_type = change.resource_type,
+            properties,
+            "{} will be updated in place.",
+            change.logical_resource_id
+        );
+    }
+}
+
+/// Change the parameters of an existing stack through a change set, so the
+/// instance and its public DNS are kept whenever CloudFormation allows.
+/// Replacing resources needs `allow_replacement`, otherwise the change set is
+/// only shown and discarded.
+#[allow(clippy::too_many_arguments)]
+#[instrument(level = "debug", skip(client))]
+pub async fn update(
+    client: &Client,
+    stack_name: &str,
+    instance_type: Option<String>,
+    disk_size: Option<usize>,
+    port: Option<u16>,
+    ssh_location: Option<String>,
+    service_locations: Vec<Source>,
+    allow_replacement: bool,
+    waiter: &StackWaiter,
+) -> Result<UpdateOutput, Error> {
+    let started = Instant::now();
+    let stack = utilities::get_stack(client, stack_name).await?;
+    if let Some(instance_type) = &instance_type {
+        let current = utilities::stack_parameter(&stack, "InstanceType").unwrap_or_default();
+        if let (Some(current), Some(instance_type)) = (
+            InstanceType::lookup_or_warn(current)?,
+            InstanceType::lookup_or_warn(instance_type)?,
+        ) {
+            instance_type.check_arch(current.arch)?;
+        }
+    }
+    if let Some(port) = port {
+        check_port(&stack, port)?;
+    }
+
+    let suffix = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let template = match service_locations.is_empty() {
+        true => None,
+        false => {
+            ingress::warn_if_public(&service_locations);
+            Some(service_template(client, &stack, &service_locations).await?)
+        }
+    };
+    // Only the parameters the stack was created with, as older stacks
+    // predate some of those in the current template
+    let parameters: Vec<Parameter> = stack
+        .parameters()
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|parameter| parameter.parameter_key())
+        .map(|key| {
+            let value = match key {
+                "InstanceType" => instance_type.clone(),
+                "DiskSize" => disk_size.map(|size| size.to_string()),
+                "Port" => port.map(|port| port.to_string()),
+                "SSHLocation" => ssh_location.clone(),
+                "ServiceLocations" if !service_locations.is_empty() => {
+                    Some(ingress::service_locations(&service_locations))
+                }
+                _ => None,
+            };
+            parameter(key, value)
+        })
+        .collect();
+
+    let change_set_name = format!("nitrogen-update-{}", suffix);
+    let mut change_set_id = create_change_set(
+        client,
+        stack_name,
+        &change_set_name,
+        template.as_deref(),
+        &parameters,
+    )
+    .await?;
+    let mut changes =
+        match utilities::describe_change_set(client, stack_name, &change_set_id, waiter).await? {
+            Some(changes) => changes,
+            None => {
+                info!("Stack '{}' is already up to date.", stack_name);
+                delete_change_set(client, stack_name, &change_set_id).await?;
+                return Ok(UpdateOutput {
+                    name: stack_name.to_string(),
+                    changes: vec![],
+                    executed: false,
+                    outputs: utilities::get_stack_outputs(client, stack_name).await?,
+                    elapsed_secs: started.elapsed().as_secs_f64(),
+                });
+            }
+        };
+
+    if allow_replacement && changes.iter().any(replaces_instance) {
+        // The host key wait condition completes only once, so a new instance
+        // publishes its key through a new one
+        info!("The instance will be replaced, publishing its host key under a new wait condition.");
+        delete_change_set(client, stack_name, &change_set_id).await?;
+        let template = match template {
+            Some(template) => template,
+            None => stack_template(client, &stack).await?,
+        };
+        let change_set_name = format!("{}-host-key", change_set_name);
+        change_set_id = create_change_set(
+            client,
+            stack_name,
+            &change_set_name,
+            Some(&renew_host_key(&template, suffix)?),
+            &parameters,
+        )
+        .await?;
+        changes = utilities::describe_change_set(client, stack_name, &change_set_id, waiter)
+            .await?
+            .unwrap_or_default();
+    }
+    changes.iter().for_each(log_change);
+
+    if changes.iter().any(ResourceChange::is_replaced) && !allow_replacement {
+        delete_change_set(client, stack_name, &change_set_id).await?;
+        return Err(failure::err_msg(
+            "The update replaces resources, which may change the instance and its address. \
+            Pass --allow-replacement to go ahead.",
+        ));
+    }
+
+    info!("Executing change set, this may take a few minutes.");
+    let mut events = StackEvents::since_now(client, stack_name).await?;
+    client
+        .execute_change_set()
+        .stack_name(stack_name)
+        .change_set_name(&change_set_id)
+        .send()
+        .await?;
+    let outcome = waiter
+        .wait(client, &mut events, StackOperation::Update)
+        .await?;
+    if outcome.progress != StackProgress::Succeeded {
+        return Err(events.failure(StackOperation::Update, &outcome));
+    }
+    info!("Successfully updated enclave instance.");
+
+    let outputs = utilities::get_stack_outputs(client, stack_name).await?;
+    known_hosts::for_stack(stack_name, &outputs)?;
+
+    Ok(UpdateOutput {
+        name: stack_name.to_string(),
+        changes,
+        executed: true,
+        outputs,
+        elapsed_secs: started.elapsed().as_secs_f64(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cf_utilities::StackOutputs;
+    use crate::state::{self, Deployment, StackState};
+    use crate::template::SETUP_TEMPLATE;
+    use serde_json::json;
+    use std::fs;
+
+    #[test]
+    fn renames_keys_and_references() {
+        let mut value = json!({
+            "Old": {"Type": "AWS::CloudFormation::WaitConditionHandle"},
+            "Other": {
+                "Handle": {"Ref": "Old"},
+                "Data": {"Fn::GetAtt": ["Old", "Data"]},
+                "Description": "Old handle",
+                "OldName": "kept"
+            }
+        });
+        rename(&mut value, "Old", "New");
+        assert_eq!(
+            value,
+            json!({
+                "New": {"Type": "AWS::CloudFormation::WaitConditionHandle"},
+                "Other": {
+                    "Handle": {"Ref": "New"},
+                    "Data": {"Fn::GetAtt": ["New", "Data"]},
+                    "Description": "Old handle",
+                    "OldName": "kept"
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn renews_the_host_key_wait_condition() {
+        let renewed = renew_host_key(SETUP_TEMPLATE, 1700000000).unwrap();
+        let template: Value = serde_json::from_str(&renewed).unwrap();
+        let resources = &template["Resources"];
+        assert!(resources.get("HostKeyWaitHandle").is_none());
+        assert!(resources.get("HostKeyWaitCondition").is_none());
+        assert_eq!(
+            resources["HostKeyWaitCondition1700000000"]["Properties"]["Handle"],
+            json!({"Ref": "HostKeyWaitHandle1700000000"})
+        );
+        assert_eq!(
+            template["Outputs"]["HostKey"]["Value"],
+            json!({"Fn::GetAtt": ["HostKeyWaitCondition1700000000", "Data"]})
+        );
+        assert!(!renewed.contains(r#""HostKeyWaitHandle""#));
+
+        // A stack renewed before is renewed again under the new suffix
+        let again: Value =
+            serde_json::from_str(&renew_host_key(&renewed, 1800000000).unwrap()).unwrap();
+        assert!(again["Resources"]
+            .get("HostKeyWaitHandle1800000000")
+            .is_some());
+        assert!(again["Resources"]
+            .get("HostKeyWaitHandle1700000000")
+            .is_none());
+    }
+
+    #[test]
+    fn renewing_needs_a_host_key_wait_condition() {
+        let err = renew_host_key(r#"{"Resources": {"EC2Instance": {}}}"#, 1).unwrap_err();
+        assert!(err.to_string().contains("HostKeyWaitHandle"));
+        assert!(renew_host_key("not json", 1).is_err());
+    }
+
+    fn outputs(ip: &str, host_key: &str) -> StackOutputs {
+        StackOutputs {
+            stack_id: "arn:aws:cloudformation:us-east-1:123456789012:stack/replaced/1".to_string(),
+            instance_id: format!("i-{}", ip.replace('.', "")),
+            public_ip: None,
+            availability_zone: "us-east-1a".to_string(),
+            public_dns: None,
+            private_ip: Some(ip.to_string()),
+            private_dns: None,
+            host_key: Some(host_key.to_string()),
+        }
+    }
+
+    const OLD_KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOXGhVQhaOkQCI+3PDOa9lNvWpJplkLAGR4vMWS7INWt";
+    const NEW_KEY: &str = "ecdsa-sha2-nistp256 \
+        AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBBu6UJlII4GXl5xpgiTR5lzjr/tRoSMArAkxV\
+        dTtof3j/1AWrwGxFffxeJ1KAcn6pbXU5eiwZJ9SgQdEnB6Bhtw=";
+
+    #[test]
+    fn replacement_rewrites_state_and_known_hosts() {
+        let dir = state::use_temp_dir("update-replacement");
+        let before = outputs("10.0.0.5", OLD_KEY);
+        let known_hosts = known_hosts::for_stack("replaced", &before).unwrap();
+        let mut state = StackState {
+            name: "replaced".to_string(),
+            region: Some("us-east-1".to_string()),
+            outputs: before,
+            instance_type: "c5.xlarge".to_string(),
+            ports: vec!["5000".parse().unwrap()],
+            service_locations: vec![],
+            ssh_location: "203.0.113.1/32".to_string(),
+            public_key: "/home/user/.ssh/id_ed25519.pub".to_string(),
+            private_key: None,
+            deployment: Some(Deployment {
+                eif: "/home/user/app.eif".to_string(),
+                eif_sha384: "00".to_string(),
+                cpu_count: 2,
+                memory_mib: 2048,
+                debug_mode: false,
+                enclave_id: "i-1000005-enc0123456789abcdef".to_string(),
+                egress: vec![],
+            }),
+        };
+        state.save().unwrap();
+
+        let after = outputs("10.0.0.9", &format!("{} root@ip-10-0-0-9", NEW_KEY));
+        let update = UpdateOutput {
+            name: "replaced".to_string(),
+            changes: vec![ResourceChange {
+                action: "Modify".to_string(),
+                logical_resource_id: "EC2Instance".to_string(),
+                resource_type: "AWS::EC2::Instance".to_string(),
+                replacement: Some("True".to_string()),
+                properties: vec!["InstanceType".to_string()],
+            }],
+            executed: true,
+            outputs: after.clone(),
+            elapsed_secs: 1.0,
+        };
+        state.record_update(
+            &update,
+            Some("c5.2xlarge".to_string()),
+            Some(8080),
+            None,
+            vec!["10.0.0.0/8".parse().unwrap()],
+        );
+        state.save().unwrap();
+        assert_eq!(
+            known_hosts::for_stack("replaced", &after).unwrap(),
+            known_hosts
+        );
+
+        let state = StackState::load("replaced").unwrap().unwrap();
+        assert!(state.deployment.is_none());
+        assert_eq!(state.outputs.instance_id, after.instance_id);
+        assert_eq!(state.instance_type, "c5.2xlarge");
+        assert_eq!(state.ports[0].host, 8080);
+        assert_eq!(state.ssh_location, "203.0.113.1/32");
+        assert_eq!(state.service_locations, ["10.0.0.0/8".parse().unwrap()]);
+        let pinned = fs::read_to_string(&known_hosts).unwrap();
+        assert_eq!(pinned, format!("10.0.0.9 {}\n", NEW_KEY));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,75 @@
+use crate::commands::build::{self, Signing};
+use crate::manifest::BuildManifest;
+use crate::output::VerifyBuildOutput;
+use failure::Error;
+use std::fs;
+use std::path::Path;
+use tracing::{info, instrument, warn};
+
+/// Rebuild the EIF described by its manifest and compare the result to what
+/// was recorded, to check that the build is reproducible.
+#[instrument(level = "debug")]
+pub async fn verify_build(
+    eif: &str,
+    private_key: Option<&str>,
+) -> Result<VerifyBuildOutput, Error> {
+    let recorded = BuildManifest::load_for(Path::new(eif))?;
+    // PCR8 only matches when the rebuild is signed with the same certificate
+    let signing = match (&recorded.signing_certificate, private_key) {
+        (Some(certificate), Some(private_key)) => Some(Signing {
+            certificate: certificate.clone(),
+            private_key: private_key.to_string(),
+        }),
+        (Some(certificate), None) => {
+            return Err(failure::err_msg(format!(
+                "EIF was signed with {}, pass its --private-key to rebuild it.",
+                certificate
+            )))
+        }
+        (None, _) => None,
+    };
+    let file_name = Path::new(&recorded.eif)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("nitrogen.eif");
+    let rebuilt_eif = format!(".verify-{}", file_name);
+
+    info!(
+        context = recorded.context,
+        dockerfile = recorded.dockerfile,
+        "Rebuilding EIF."
+    );
+    let rebuilt = build::build(
+        &recorded.context,
+        &recorded.dockerfile,
+        &rebuilt_eif,
+        signing.as_ref(),
+        recorded.arch,
+    )
+    .await?;
+    for path in [
+        Path::new(&rebuilt.manifest.eif),
+        Path::new(&rebuilt.manifest_path),
+    ] {
+        if let Err(err) = fs::remove_file(path) {
+            warn!("Unable to remove {}: {}", path.display(), err);
+        }
+    }
+
+    let differences = recorded.diff(&rebuilt.manifest);
+    for difference in &differences {
+        if difference.measured {
+            warn!("Rebuilt EIF differs, {}", difference);
+        } else {
+            info!("Build input changed, {}", difference);
+        }
+    }
+
+    Ok(VerifyBuildOutput {
+        eif: eif.to_string(),
+        reproducible: differences.iter().all(|difference| !difference.measured),
+        recorded,
+        rebuilt: rebuilt.manifest,
+        differences,
+    })
+}
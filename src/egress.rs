@@ -0,0 +1,214 @@
+use crate::remote::RemoteHost;
+use failure::Error;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use tracing::info;
+
+/// Vsock port of the proxy for the first allowed destination; the others
+/// follow in the order given, as `examples/egress/egress.sh` expects.
+pub const FIRST_VSOCK_PORT: u32 = 8000;
+const CONFIG: &str = "/etc/nitro_enclaves/nitrogen-egress.yaml";
+/// Template unit of the proxies, one instance per vsock port. Enabled
+/// instances start again when the instance is stopped and started.
+const UNIT: &str = "/etc/systemd/system/nitrogen-egress@.service";
+/// Destination of each proxy, named after its vsock port.
+const ENVIRONMENT_DIR: &str = "/etc/nitro_enclaves";
+
+/// A destination the enclave may connect out to, written `host:port`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Destination {
+    pub host: String,
+    pub port: u16,
+}
+
+impl FromStr for Destination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("'{}' is not a destination of the form host:port", s);
+        let (host, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+        // Hosts end up in a shell command and a YAML file on the instance
+        if host.is_empty()
+            || !host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+        {
+            return Err(invalid());
+        }
+        match port.parse() {
+            Ok(0) | Err(_) => Err(invalid()),
+            Ok(port) => Ok(Destination {
+                host: host.to_string(),
+                port,
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+/// A vsock-proxy running on the host for one destination.
+#[derive(Clone, Debug, Serialize)]
+pub struct EgressProxy {
+    pub host: String,
+    pub port: u16,
+    pub vsock_port: u32,
+}
+
+fn unit_file() -> String {
+    format!(
+        "[Unit]\n\
+        Description=nitrogen enclave egress proxy on vsock port %i\n\
+        After=network-online.target nitro-enclaves-allocator.service\n\
+        \n\
+        [Service]\n\
+        EnvironmentFile={}/nitrogen-egress-%i.env\n\
+        ExecStart=/usr/bin/vsock-proxy %i ${{HOST}} ${{PORT}} --config {}\n\
+        Restart=always\n\
+        \n\
+        [Install]\n\
+        WantedBy=multi-user.target\n",
+        ENVIRONMENT_DIR, CONFIG
+    )
+}
+
+fn allowlist(destinations: &[Destination]) -> String {
+    let entries: String = destinations
+        .iter()
+        .map(|d| format!("- {{address: {}, port: {}}}\n", d.host, d.port))
+        .collect();
+    format!("allowlist:\n{}", entries)
+}
+
+/// Write `contents`, which must not contain single quotes, to a root-owned file.
+fn write_file(remote: &RemoteHost, path: &str, contents: &str) -> Result<(), Error> {
+    remote.exec(&format!(
+        "printf '%s' '{}' | sudo tee {} >/dev/null",
+        contents, path
+    ))?;
+    Ok(())
+}
+
+/// Replace the host's vsock-proxies with one per destination, only allowed to
+/// reach that destination. An empty list stops all of them. The proxies are
+/// systemd units enabled at boot, so they survive stopping the instance.
+pub fn configure(
+    remote: &RemoteHost,
+    destinations: &[Destination],
+) -> Result<Vec<EgressProxy>, Error> {
+    // Also stops the transient `nitrogen-egress-<port>` units of older
+    // versions. Failed units keep their name reserved until reset
+    remote.exec(&format!(
+        "units=$(systemctl list-units --all --plain --no-legend 'nitrogen-egress*' \
+        | awk '{{print $1}}'); [ -z \"$units\" ] || sudo systemctl stop $units; \
+        sudo rm -f /etc/systemd/system/multi-user.target.wants/nitrogen-egress@*.service \
+        {}/nitrogen-egress-*.env; \
+        sudo systemctl reset-failed 'nitrogen-egress*' 2>/dev/null || true",
+        ENVIRONMENT_DIR
+    ))?;
+    if destinations.is_empty() {
+        return Ok(vec![]);
+    }
+
+    write_file(remote, CONFIG, &allowlist(destinations))?;
+    write_file(remote, UNIT, &unit_file())?;
+    remote.exec("sudo systemctl daemon-reload")?;
+
+    let mut proxies = vec![];
+    for (vsock_port, destination) in (FIRST_VSOCK_PORT..).zip(destinations) {
+        write_file(
+            remote,
+            &format!("{}/nitrogen-egress-{}.env", ENVIRONMENT_DIR, vsock_port),
+            &format!("HOST={}\nPORT={}\n", destination.host, destination.port),
+        )?;
+        remote.exec(&format!(
+            "sudo systemctl enable --now nitrogen-egress@{}.service",
+            vsock_port
+        ))?;
+        info!(vsock_port, "Forwarding enclave egress to {}.", destination);
+        proxies.push(EgressProxy {
+            host: destination.host.clone(),
+            port: destination.port,
+            vsock_port,
+        });
+    }
+    Ok(proxies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_destinations() {
+        assert_eq!(
+            "kms.us-east-1.amazonaws.com:443".parse(),
+            Ok(Destination {
+                host: "kms.us-east-1.amazonaws.com".to_string(),
+                port: 443
+            })
+        );
+        let destination: Destination = "10.0.0.5:65535".parse().unwrap();
+        assert_eq!(destination.port, 65535);
+        assert_eq!(destination.to_string(), "10.0.0.5:65535");
+    }
+
+    #[test]
+    fn rejects_malformed_destinations() {
+        for s in [
+            "",
+            "example.com",
+            "example.com:",
+            ":443",
+            "example.com:0",
+            "example.com:65536",
+            "example.com:-1",
+            "example.com:https",
+            "exa mple.com:443",
+            "example.com';reboot;':443",
+            "[2001:db8::1]:443",
+        ] {
+            assert_eq!(
+                s.parse::<Destination>(),
+                Err(format!(
+                    "'{}' is not a destination of the form host:port",
+                    s
+                )),
+                "{}",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn renders_an_allowlist_entry_per_destination() {
+        let destinations: Vec<Destination> = ["kms.us-east-1.amazonaws.com:443", "10.0.0.5:8080"]
+            .iter()
+            .map(|d| d.parse().unwrap())
+            .collect();
+        assert_eq!(
+            allowlist(&destinations),
+            "allowlist:\n\
+            - {address: kms.us-east-1.amazonaws.com, port: 443}\n\
+            - {address: 10.0.0.5, port: 8080}\n"
+        );
+    }
+
+    #[test]
+    fn proxies_are_enabled_at_boot() {
+        let unit = unit_file();
+        assert!(unit.contains("EnvironmentFile=/etc/nitro_enclaves/nitrogen-egress-%i.env\n"));
+        assert!(unit.contains(
+            "ExecStart=/usr/bin/vsock-proxy %i ${HOST} ${PORT} --config \
+            /etc/nitro_enclaves/nitrogen-egress.yaml\n"
+        ));
+        assert!(unit.ends_with("[Install]\nWantedBy=multi-user.target\n"));
+        // Written through a single-quoted shell string
+        assert!(!unit.contains('\''));
+    }
+}
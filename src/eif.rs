@@ -0,0 +1,572 @@
+use crate::arch::Arch;
+use crate::enclave::Measurements;
+use failure::Error;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha384};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use x509_parser::pem::parse_x509_pem;
+
+/// Reader for the Enclave Image File format produced by `build-enclave`.
+/// https://github.com/aws/aws-nitro-enclaves-image-format
+const EIF_MAGIC: [u8; 4] = *b".eif";
+const MAX_SECTIONS: usize = 32;
+/// magic, version, flags, default memory and CPUs, reserved, section count,
+/// section offsets and sizes, unused, CRC32. All fields are big endian.
+const HEADER_SIZE: usize = 4 + 2 + 2 + 8 + 8 + 2 + 2 + 8 * MAX_SECTIONS * 2 + 4 + 4;
+/// Section type, flags and data size.
+const SECTION_HEADER_SIZE: usize = 2 + 2 + 8;
+const ARCH_ARM64_FLAG: u16 = 0x1;
+/// Algorithm `nitro-cli` reports for the measurements of an EIF.
+const HASH_ALGORITHM: &str = "Sha384 { ... }";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SectionKind {
+    Kernel,
+    Cmdline,
+    Ramdisk,
+    Signature,
+    Metadata,
+    Unknown(u16),
+}
+
+impl From<u16> for SectionKind {
+    fn from(value: u16) -> Self {
+        match value {
+            1 => SectionKind::Kernel,
+            2 => SectionKind::Cmdline,
+            3 => SectionKind::Ramdisk,
+            4 => SectionKind::Signature,
+            5 => SectionKind::Metadata,
+            other => SectionKind::Unknown(other),
+        }
+    }
+}
+
+impl fmt::Display for SectionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SectionKind::Kernel => write!(f, "kernel"),
+            SectionKind::Cmdline => write!(f, "cmdline"),
+            SectionKind::Ramdisk => write!(f, "ramdisk"),
+            SectionKind::Signature => write!(f, "signature"),
+            SectionKind::Metadata => write!(f, "metadata"),
+            SectionKind::Unknown(kind) => write!(f, "unknown ({})", kind),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Section {
+    pub kind: SectionKind,
+    /// Offset of the section header in the file.
+    pub offset: u64,
+    /// Size of the section data, excluding its header.
+    pub size: u64,
+}
+
+/// Entry of the signature section, as written by `build-enclave --signing-certificate`.
+#[derive(Deserialize)]
+struct PcrSignature {
+    signing_certificate: ByteBuf,
+}
+
+/// Everything an EIF says about itself, with the PCRs the enclave will be
+/// measured with computed from its sections.
+#[derive(Clone, Debug, Serialize)]
+pub struct Eif {
+    pub version: u16,
+    pub arch: Arch,
+    pub default_memory: u64,
+    pub default_cpus: u64,
+    /// Length of the whole file, which `nitro-cli` sizes enclave memory from.
+    pub file_size: u64,
+    pub sections: Vec<Section>,
+    pub cmdline: Option<String>,
+    /// Identity metadata embedded by the builder (image name, build time, docker info).
+    pub metadata: Option<serde_json::Value>,
+    pub signed: bool,
+    pub crc32: String,
+    pub computed_crc32: String,
+    pub measurements: Measurements,
+}
+
+/// Hashers feeding the PCRs. PCR0 covers the whole image, PCR1 the kernel,
+/// cmdline and first (bootstrap) ramdisk, and PCR2 the remaining ramdisks.
+#[derive(Default)]
+struct PcrHashers {
+    image: Sha384,
+    bootstrap: Sha384,
+    application: Sha384,
+}
+
+/// Value of a PCR extended once, from zero, with the digest of its data.
+fn extend_pcr(hasher: Sha384) -> String {
+    let mut pcr = Sha384::new();
+    pcr.update([0u8; 48]);
+    pcr.update(hasher.finalize());
+    hex::encode(pcr.finalize())
+}
+
+fn be_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn be_u32(bytes: &[u8], at: usize) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&bytes[at..at + 4]);
+    u32::from_be_bytes(buf)
+}
+
+fn be_u64(bytes: &[u8], at: usize) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[at..at + 8]);
+    u64::from_be_bytes(buf)
+}
+
+impl Eif {
+    /// Parse the EIF at `path`, streaming each section through the CRC and
+    /// PCR hashers so large ramdisks are never held in memory.
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) => {
+                return Err(failure::err_msg(format!(
+                    "Unable to open EIF {}: {}",
+                    path.display(),
+                    err
+                )))
+            }
+        };
+        let file_size = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+
+        let mut header = [0u8; HEADER_SIZE];
+        if reader.read_exact(&mut header).is_err() || header[..4] != EIF_MAGIC {
+            return Err(failure::err_msg(format!(
+                "{} is not an enclave image file.",
+                path.display()
+            )));
+        }
+        let version = be_u16(&header, 4);
+        let flags = be_u16(&header, 6);
+        let default_memory = be_u64(&header, 8);
+        let default_cpus = be_u64(&header, 16);
+        let num_sections = be_u16(&header, 26) as usize;
+        if num_sections > MAX_SECTIONS {
+            return Err(failure::err_msg(format!(
+                "EIF {} declares {} sections, at most {} are supported.",
+                path.display(),
+                num_sections,
+                MAX_SECTIONS
+            )));
+        }
+        let offsets_at = 28;
+        let sizes_at = offsets_at + 8 * MAX_SECTIONS;
+        let crc32 = be_u32(&header, HEADER_SIZE - 4);
+
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(&header[..HEADER_SIZE - 4]);
+        let mut hashers = PcrHashers::default();
+        let mut ramdisks = 0;
+        let mut sections = vec![];
+        let mut cmdline = None;
+        let mut metadata = None;
+        let mut certificate = None;
+
+        for index in 0..num_sections {
+            let offset = be_u64(&header, offsets_at + 8 * index);
+            let size = be_u64(&header, sizes_at + 8 * index);
+            if offset
+                .saturating_add(SECTION_HEADER_SIZE as u64)
+                .saturating_add(size)
+                > file_size
+            {
+                return Err(failure::err_msg(format!(
+                    "EIF {} is truncated, section {} ends past the end of the file.",
+                    path.display(),
+                    index
+                )));
+            }
+
+            reader.seek(SeekFrom::Start(offset))?;
+            let mut section_header = [0u8; SECTION_HEADER_SIZE];
+            reader.read_exact(&mut section_header)?;
+            crc.update(&section_header);
+            let kind = SectionKind::from(be_u16(&section_header, 0));
+            if be_u64(&section_header, 4) != size {
+                return Err(failure::err_msg(format!(
+                    "EIF {} is corrupt, the size of section {} ({}) disagrees with the header.",
+                    path.display(),
+                    index,
+                    kind
+                )));
+            }
+
+            // Only the small sections are kept, the rest is streamed into the hashers
+            let keep = matches!(
+                kind,
+                SectionKind::Cmdline | SectionKind::Metadata | SectionKind::Signature
+            );
+            let mut kept = vec![];
+            let mut remaining = size;
+            let mut chunk = vec![0u8; 64 * 1024];
+            while remaining > 0 {
+                let len = remaining.min(chunk.len() as u64) as usize;
+                reader.read_exact(&mut chunk[..len])?;
+                let data = &chunk[..len];
+                crc.update(data);
+                match kind {
+                    SectionKind::Kernel | SectionKind::Cmdline => {
+                        hashers.image.update(data);
+                        hashers.bootstrap.update(data);
+                    }
+                    SectionKind::Ramdisk => {
+                        hashers.image.update(data);
+                        if ramdisks == 0 {
+                            hashers.bootstrap.update(data);
+                        } else {
+                            hashers.application.update(data);
+                        }
+                    }
+                    _ => {}
+                }
+                if keep {
+                    kept.extend_from_slice(data);
+                }
+                remaining -= len as u64;
+            }
+
+            match kind {
+                SectionKind::Ramdisk => ramdisks += 1,
+                SectionKind::Cmdline => {
+                    cmdline = Some(
+                        String::from_utf8_lossy(&kept)
+                            .trim_end_matches('\0')
+                            .to_string(),
+                    )
+                }
+                SectionKind::Metadata => metadata = serde_json::from_slice(&kept).ok(),
+                SectionKind::Signature => certificate = Some(signing_certificate(&kept)?),
+                _ => {}
+            }
+            sections.push(Section { kind, offset, size });
+        }
+
+        let pcr8 = certificate.map(|der| {
+            let mut hasher = Sha384::new();
+            hasher.update(der);
+            extend_pcr(hasher)
+        });
+
+        Ok(Eif {
+            version,
+            arch: if flags & ARCH_ARM64_FLAG != 0 {
+                Arch::Aarch64
+            } else {
+                Arch::X86_64
+            },
+            default_memory,
+            default_cpus,
+            file_size,
+            sections,
+            cmdline,
+            metadata,
+            signed: pcr8.is_some(),
+            crc32: format!("{:08x}", crc32),
+            computed_crc32: format!("{:08x}", crc.finalize()),
+            measurements: Measurements {
+                hash_algorithm: Some(HASH_ALGORITHM.to_string()),
+                pcr0: extend_pcr(hashers.image),
+                pcr1: extend_pcr(hashers.bootstrap),
+                pcr2: extend_pcr(hashers.application),
+                pcr8,
+            },
+        })
+    }
+
+    /// Total size of the sections of `kind`.
+    pub fn section_size(&self, kind: SectionKind) -> u64 {
+        self.sections
+            .iter()
+            .filter(|section| section.kind == kind)
+            .map(|section| section.size)
+            .sum()
+    }
+
+    /// Check the EIF is intact and bootable before shipping it to an instance.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.crc32 != self.computed_crc32 {
+            return Err(failure::err_msg(format!(
+                "EIF is corrupt, its CRC32 is {} but its contents hash to {}.",
+                self.crc32, self.computed_crc32
+            )));
+        }
+        for kind in [
+            SectionKind::Kernel,
+            SectionKind::Cmdline,
+            SectionKind::Ramdisk,
+        ] {
+            if !self.sections.iter().any(|section| section.kind == kind) {
+                return Err(failure::err_msg(format!("EIF has no {} section.", kind)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// DER of the certificate the EIF was signed with. PCR8 is measured over it.
+fn signing_certificate(section: &[u8]) -> Result<Vec<u8>, Error> {
+    let signatures: Vec<PcrSignature> = match serde_cbor::from_slice(section) {
+        Ok(signatures) => signatures,
+        Err(err) => {
+            return Err(failure::err_msg(format!(
+                "Malformed EIF signature section: {}",
+                err
+            )))
+        }
+    };
+    let certificate = match signatures.into_iter().next() {
+        Some(signature) => signature.signing_certificate.into_vec(),
+        None => return Err(failure::err_msg("EIF signature section is empty.")),
+    };
+    match parse_x509_pem(&certificate) {
+        Ok((_, pem)) => Ok(pem.contents),
+        Err(_) => Err(failure::err_msg(
+            "Unable to parse the EIF signing certificate.",
+        )),
+    }
+}
+
+impl fmt::Display for Eif {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "version:        {}", self.version)?;
+        writeln!(f, "arch:           {}", self.arch)?;
+        writeln!(
+            f,
+            "crc32:          {}{}",
+            self.crc32,
+            if self.crc32 == self.computed_crc32 {
+                String::new()
+            } else {
+                format!(" (contents hash to {})", self.computed_crc32)
+            }
+        )?;
+        writeln!(f, "signed:         {}", self.signed)?;
+        writeln!(f, "size:           {} bytes", self.file_size)?;
+        if let Some(cmdline) = &self.cmdline {
+            writeln!(f, "cmdline:        {}", cmdline)?;
+        }
+        writeln!(f, "sections:")?;
+        for section in &self.sections {
+            writeln!(
+                f,
+                "  {:<10} {:>12} bytes at {}",
+                section.kind.to_string(),
+                section.size,
+                section.offset
+            )?;
+        }
+        if let Some(metadata) = &self.metadata {
+            for field in ["ImageName", "ImageVersion"] {
+                if let Some(value) = metadata.get(field).and_then(|value| value.as_str()) {
+                    writeln!(f, "{:<15} {}", format!("{}:", field), value)?;
+                }
+            }
+            if let Some(build) = metadata.get("BuildMetadata").and_then(|b| b.as_object()) {
+                for (field, value) in build {
+                    writeln!(
+                        f,
+                        "{:<15} {}",
+                        format!("{}:", field),
+                        value.as_str().unwrap_or(&value.to_string())
+                    )?;
+                }
+            }
+        }
+        writeln!(f, "PCR0:           {}", self.measurements.pcr0)?;
+        writeln!(f, "PCR1:           {}", self.measurements.pcr1)?;
+        write!(f, "PCR2:           {}", self.measurements.pcr2)?;
+        if let Some(pcr8) = &self.measurements.pcr8 {
+            write!(f, "\nPCR8:           {}", pcr8)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::path::PathBuf;
+
+    const KERNEL: &[u8] = b"kernel";
+    const CMDLINE: &[u8] = b"console=ttyS0\0";
+    const BOOTSTRAP: &[u8] = b"bootstrap";
+    const APPLICATION: &[u8] = b"application";
+    const METADATA: &[u8] = br#"{"ImageName":"test"}"#;
+    /// A PEM wrapping the bytes 00 01 02 03, enough for PCR8 which only hashes the DER.
+    const CERTIFICATE: &str = "-----BEGIN CERTIFICATE-----\nAAECAw==\n-----END CERTIFICATE-----\n";
+
+    /// An EIF laid out as `build-enclave` does: the header, then each
+    /// section's header and data back to back.
+    fn eif_bytes(flags: u16, sections: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut offsets = vec![];
+        let mut body = vec![];
+        for (kind, data) in sections {
+            offsets.push((HEADER_SIZE + body.len()) as u64);
+            body.extend_from_slice(&kind.to_be_bytes());
+            body.extend_from_slice(&0u16.to_be_bytes());
+            body.extend_from_slice(&(data.len() as u64).to_be_bytes());
+            body.extend_from_slice(data);
+        }
+        let mut eif = EIF_MAGIC.to_vec();
+        eif.extend_from_slice(&4u16.to_be_bytes());
+        eif.extend_from_slice(&flags.to_be_bytes());
+        eif.extend_from_slice(&(512u64 << 20).to_be_bytes());
+        eif.extend_from_slice(&2u64.to_be_bytes());
+        eif.extend_from_slice(&0u16.to_be_bytes());
+        eif.extend_from_slice(&(sections.len() as u16).to_be_bytes());
+        for index in 0..MAX_SECTIONS {
+            eif.extend_from_slice(&offsets.get(index).copied().unwrap_or(0).to_be_bytes());
+        }
+        for index in 0..MAX_SECTIONS {
+            let size = sections.get(index).map_or(0, |(_, data)| data.len() as u64);
+            eif.extend_from_slice(&size.to_be_bytes());
+        }
+        eif.extend_from_slice(&0u32.to_be_bytes());
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(&eif);
+        crc.update(&body);
+        eif.extend_from_slice(&crc.finalize().to_be_bytes());
+        eif.extend_from_slice(&body);
+        eif
+    }
+
+    fn unsigned_sections() -> Vec<(u16, &'static [u8])> {
+        vec![
+            (1, KERNEL),
+            (2, CMDLINE),
+            (3, BOOTSTRAP),
+            (3, APPLICATION),
+            (5, METADATA),
+        ]
+    }
+
+    fn write(name: &str, bytes: &[u8]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("nitrogen-{}-{}.eif", name, std::process::id()));
+        fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    fn read(name: &str, bytes: &[u8]) -> Result<Eif, Error> {
+        let path = write(name, bytes);
+        let eif = Eif::read(&path);
+        fs::remove_file(path).unwrap();
+        eif
+    }
+
+    #[test]
+    fn reads_sections_crc_and_pcrs() {
+        let bytes = eif_bytes(0, &unsigned_sections());
+        let eif = read("unsigned", &bytes).unwrap();
+        assert_eq!(eif.version, 4);
+        assert_eq!(eif.file_size, bytes.len() as u64);
+        assert_eq!(eif.arch, Arch::X86_64);
+        assert_eq!(eif.default_memory, 512 << 20);
+        assert_eq!(eif.default_cpus, 2);
+        assert_eq!(eif.cmdline.as_deref(), Some("console=ttyS0"));
+        assert_eq!(eif.metadata.as_ref().unwrap()["ImageName"], "test");
+        assert_eq!(eif.section_size(SectionKind::Ramdisk), 20);
+        assert_eq!(
+            eif.sections[2].offset,
+            HEADER_SIZE as u64 + 12 + 6 + 12 + 14
+        );
+        assert_eq!(eif.crc32, "1e6b9595");
+        assert_eq!(eif.computed_crc32, "1e6b9595");
+        assert!(!eif.signed);
+        assert_eq!(
+            eif.measurements.pcr0,
+            "538cdaa5574a474d9f7c9f5ce19cf26ac4de8ee562209b59758c3c813277ec6d\
+            05016eeb1f48ade53168254c650dc79d"
+        );
+        assert_eq!(
+            eif.measurements.pcr1,
+            "9ac0e49a2515060be99858037f940ff94184f373190c10a77cd54a1c05e7fa5b\
+            c016e9717aecac11d30273d0481f29ae"
+        );
+        assert_eq!(
+            eif.measurements.pcr2,
+            "8a45599e5e77b24c4b6472869c24e10490c4d5df715fbd0811fc4b33d370276c\
+            f00bb24871d3a1987587d6b9283d4f42"
+        );
+        assert_eq!(eif.measurements.pcr8, None);
+    }
+
+    #[test]
+    fn reads_arm64_flag() {
+        let eif = read("arm64", &eif_bytes(ARCH_ARM64_FLAG, &unsigned_sections())).unwrap();
+        assert_eq!(eif.arch, Arch::Aarch64);
+        assert_eq!(eif.crc32, "18bbb8b5");
+        eif.validate().unwrap();
+    }
+
+    #[test]
+    fn measures_signing_certificate_as_pcr8() {
+        let mut signature = BTreeMap::new();
+        signature.insert(
+            "signing_certificate",
+            ByteBuf::from(CERTIFICATE.as_bytes().to_vec()),
+        );
+        signature.insert("signature", ByteBuf::from(vec![0u8; 4]));
+        let signature = serde_cbor::to_vec(&vec![signature]).unwrap();
+        let mut sections = unsigned_sections();
+        sections.insert(4, (4, &signature));
+
+        let eif = read("signed", &eif_bytes(0, &sections)).unwrap();
+        assert!(eif.signed);
+        assert_eq!(
+            eif.measurements.pcr8.as_deref(),
+            Some(
+                "e1128b7f91b477a55cf1a488567665df9d09261585d20d552800c1dcf5d7904a\
+                c0d95f1649843d5c103652663f371382"
+            )
+        );
+        // The signature is not measured into the other PCRs
+        assert_eq!(
+            eif.measurements.pcr0,
+            "538cdaa5574a474d9f7c9f5ce19cf26ac4de8ee562209b59758c3c813277ec6d\
+            05016eeb1f48ade53168254c650dc79d"
+        );
+    }
+
+    #[test]
+    fn validate_catches_corruption() {
+        let mut bytes = eif_bytes(0, &unsigned_sections());
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        let eif = read("corrupt", &bytes).unwrap();
+        assert_eq!(eif.crc32, "1e6b9595");
+        assert_ne!(eif.computed_crc32, eif.crc32);
+        assert!(eif.validate().is_err());
+    }
+
+    #[test]
+    fn validate_requires_bootable_sections() {
+        let eif = read("no-ramdisk", &eif_bytes(0, &[(1, KERNEL), (2, CMDLINE)])).unwrap();
+        assert!(eif.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_and_foreign_files() {
+        let bytes = eif_bytes(0, &unsigned_sections());
+        assert!(read("truncated", &bytes[..bytes.len() - 1]).is_err());
+        assert!(read("foreign", b"not an eif").is_err());
+    }
+}
@@ -0,0 +1,62 @@
+use serde::{Deserialize, Serialize};
+
+/// PCR measurements of an enclave image. Reads both the `nitro-cli` naming
+/// and our own, so recorded measurements round-trip.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Measurements {
+    #[serde(alias = "HashAlgorithm", default)]
+    pub hash_algorithm: Option<String>,
+    #[serde(alias = "PCR0")]
+    pub pcr0: String,
+    #[serde(alias = "PCR1")]
+    pub pcr1: String,
+    #[serde(alias = "PCR2")]
+    pub pcr2: String,
+    /// Hash of the signing certificate, only present for signed EIFs.
+    #[serde(alias = "PCR8", default, skip_serializing_if = "Option::is_none")]
+    pub pcr8: Option<String>,
+}
+
+/// Output of `build-enclave`, of which we only keep the measurements.
+#[derive(Deserialize)]
+pub(crate) struct BuildEnclaveOutput {
+    #[serde(rename = "Measurements")]
+    pub measurements: Measurements,
+}
+
+/// A single entry of `nitro-cli describe-enclaves`.
+/// https://docs.aws.amazon.com/enclaves/latest/user/cmd-nitro-describe-enclaves.html
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct EnclaveDescription {
+    #[serde(rename(deserialize = "EnclaveName"), default)]
+    pub enclave_name: String,
+    #[serde(rename(deserialize = "EnclaveID"))]
+    pub enclave_id: String,
+    #[serde(rename(deserialize = "ProcessID"), default)]
+    pub process_id: Option<u64>,
+    #[serde(rename(deserialize = "EnclaveCID"))]
+    pub enclave_cid: u64,
+    #[serde(rename(deserialize = "NumberOfCPUs"), default)]
+    pub cpu_count: u64,
+    #[serde(rename(deserialize = "CPUIDs"), default)]
+    pub cpu_ids: Vec<u64>,
+    #[serde(rename(deserialize = "MemoryMiB"), default)]
+    pub memory_mib: u64,
+    #[serde(rename(deserialize = "State"))]
+    pub state: String,
+    #[serde(rename(deserialize = "Flags"), default)]
+    pub flags: String,
+    #[serde(rename(deserialize = "Measurements"), default)]
+    pub measurements: Option<Measurements>,
+}
+
+impl EnclaveDescription {
+    pub fn is_running(&self) -> bool {
+        // According to the docs, the state is either "RUNNING" or "TERMINATING"
+        self.state == "RUNNING"
+    }
+
+    pub fn is_debug_mode(&self) -> bool {
+        self.flags == "DEBUG_MODE"
+    }
+}
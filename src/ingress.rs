@@ -0,0 +1,390 @@
+use crate::ports::PortMapping;
+use failure::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use tracing::warn;
+
+/// Description of the ingress rules [`render`] generates, so re-rendering
+/// replaces only those and keeps rules added by an overlay.
+const SERVICE_RULE: &str = "nitrogen service port";
+
+/// Where traffic to the forwarded ports may come from: an IPv4 or IPv6 CIDR
+/// range, or the instances of a security group.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Source {
+    Ipv4(String),
+    Ipv6(String),
+    SecurityGroup(String),
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(id) = s.strip_prefix("sg-") {
+            // Older groups have 8 hex digits, newer ones 17
+            if matches!(id.len(), 8 | 17) && id.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Ok(Source::SecurityGroup(s.to_string()));
+            }
+            return Err(format!("'{}' is not a valid security group id", s));
+        }
+        let invalid = || {
+            format!(
+                "'{}' is not an IPv4 or IPv6 CIDR range or a security group id",
+                s
+            )
+        };
+        let (address, prefix) = s.split_once('/').ok_or_else(invalid)?;
+        let prefix: u8 = prefix.parse().map_err(|_| invalid())?;
+        if address.parse::<Ipv4Addr>().is_ok() && prefix <= 32 {
+            Ok(Source::Ipv4(s.to_string()))
+        } else if address.parse::<Ipv6Addr>().is_ok() && prefix <= 128 {
+            Ok(Source::Ipv6(s.to_string()))
+        } else {
+            Err(invalid())
+        }
+    }
+}
+
+impl TryFrom<String> for Source {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Source> for String {
+    fn from(source: Source) -> Self {
+        source.to_string()
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Ipv4(cidr) | Source::Ipv6(cidr) | Source::SecurityGroup(cidr) => {
+                write!(f, "{}", cidr)
+            }
+        }
+    }
+}
+
+impl Source {
+    /// Whether the source is the whole IPv4 or IPv6 internet.
+    pub fn is_anywhere(&self) -> bool {
+        match self {
+            Source::Ipv4(cidr) | Source::Ipv6(cidr) => cidr.ends_with("/0"),
+            Source::SecurityGroup(_) => false,
+        }
+    }
+
+    /// Security group ingress rule letting this source reach `port`.
+    fn rule(&self, port: Value) -> Value {
+        let mut rule = Map::new();
+        rule.insert("Description".to_string(), json!(SERVICE_RULE));
+        rule.insert("IpProtocol".to_string(), json!("tcp"));
+        rule.insert("FromPort".to_string(), port.clone());
+        rule.insert("ToPort".to_string(), port);
+        let (key, value) = match self {
+            Source::Ipv4(cidr) => ("CidrIp", cidr),
+            Source::Ipv6(cidr) => ("CidrIpv6", cidr),
+            Source::SecurityGroup(id) => ("SourceSecurityGroupId", id),
+        };
+        rule.insert(key.to_string(), json!(value));
+        Value::Object(rule)
+    }
+}
+
+/// Warn when the forwarded ports would be reachable from the whole internet.
+pub fn warn_if_public(sources: &[Source]) {
+    if let Some(source) = sources.iter().find(|source| source.is_anywhere()) {
+        warn!(
+            "The service ports are open to the world ({}). Pass --service-location to restrict \
+            who can reach them.",
+            source
+        );
+    }
+}
+
+/// Value of the `ServiceLocations` template parameter, recording the sources
+/// the rules were generated from so `update` can regenerate them.
+pub fn service_locations(sources: &[Source]) -> String {
+    sources
+        .iter()
+        .map(Source::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Parse the `ServiceLocations` parameter of an existing stack.
+pub fn parse_service_locations(value: &str) -> Result<Vec<Source>, Error> {
+    value
+        .split(',')
+        .filter(|source| !source.is_empty())
+        .map(|source| source.parse().map_err(failure::err_msg))
+        .collect()
+}
+
+/// The template with one ingress rule per forwarded port and source, in place
+/// of the service rules it had. CloudFormation cannot loop over a parameter,
+/// so the rules are written into the security group directly. The SSH rule
+/// is kept, and the first mapping keeps referencing the `Port` parameter so
+/// that `update --port` moves it.
+pub fn render(
+    template: &str,
+    mappings: &[PortMapping],
+    sources: &[Source],
+) -> Result<String, Error> {
+    if sources.is_empty() {
+        return Err(failure::err_msg("At least one service location is needed."));
+    }
+    let mut template: Value = serde_json::from_str(template)?;
+    let ingress = match template
+        .pointer_mut("/Resources/InstanceSecurityGroup/Properties/SecurityGroupIngress")
+        .and_then(Value::as_array_mut)
+    {
+        Some(ingress) => ingress,
+        None => {
+            return Err(failure::err_msg(
+                "The template has no InstanceSecurityGroup ingress rules to add ports to.",
+            ))
+        }
+    };
+    // Templates rendered before rules were marked had no other rules on the
+    // service ports, so those are taken to be generated too
+    let marked = ingress
+        .iter()
+        .any(|rule| rule["Description"] == SERVICE_RULE);
+    let service_ports: Vec<Value> = std::iter::once(json!({ "Ref": "Port" }))
+        .chain(mappings.iter().map(|mapping| json!(mapping.host)))
+        .collect();
+    ingress.retain(|rule| match marked {
+        true => rule["Description"] != SERVICE_RULE,
+        false => rule.get("Description").is_some() || !service_ports.contains(&rule["FromPort"]),
+    });
+    for (i, mapping) in mappings.iter().enumerate() {
+        let port = match i {
+            0 => json!({ "Ref": "Port" }),
+            _ => json!(mapping.host),
+        };
+        ingress.extend(sources.iter().map(|source| source.rule(port.clone())));
+    }
+    Ok(serde_json::to_string(&template)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template::SETUP_TEMPLATE;
+
+    const INGRESS: &str = "/Resources/InstanceSecurityGroup/Properties/SecurityGroupIngress";
+
+    fn sources(sources: &[&str]) -> Vec<Source> {
+        sources.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    fn ingress(template: &str) -> Vec<Value> {
+        let template: Value = serde_json::from_str(template).unwrap();
+        template
+            .pointer(INGRESS)
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .clone()
+    }
+
+    #[test]
+    fn parses_cidr_ranges_and_security_groups() {
+        assert_eq!(
+            "10.0.0.0/8".parse(),
+            Ok(Source::Ipv4("10.0.0.0/8".to_string()))
+        );
+        assert_eq!(
+            "0.0.0.0/0".parse(),
+            Ok(Source::Ipv4("0.0.0.0/0".to_string()))
+        );
+        assert_eq!(
+            "2001:db8::/32".parse(),
+            Ok(Source::Ipv6("2001:db8::/32".to_string()))
+        );
+        assert_eq!("::/0".parse(), Ok(Source::Ipv6("::/0".to_string())));
+        assert_eq!(
+            "sg-0123abcd".parse(),
+            Ok(Source::SecurityGroup("sg-0123abcd".to_string()))
+        );
+        assert_eq!(
+            "sg-0123456789abcdef0".parse(),
+            Ok(Source::SecurityGroup("sg-0123456789abcdef0".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_sources() {
+        for s in [
+            "",
+            "10.0.0.0",
+            "10.0.0.0/33",
+            "10.0.0/8",
+            "256.0.0.0/8",
+            "2001:db8::/129",
+            "2001:db8::",
+            "example.com/24",
+            "10.0.0.0/-1",
+        ] {
+            assert_eq!(
+                s.parse::<Source>(),
+                Err(format!(
+                    "'{}' is not an IPv4 or IPv6 CIDR range or a security group id",
+                    s
+                )),
+                "{}",
+                s
+            );
+        }
+        for s in [
+            "sg-",
+            "sg-0123abc",
+            "sg-0123456789abcdefg",
+            "sg-0123456789abcdef01",
+        ] {
+            assert_eq!(
+                s.parse::<Source>(),
+                Err(format!("'{}' is not a valid security group id", s))
+            );
+        }
+    }
+
+    #[test]
+    fn round_trips_service_locations() {
+        let parsed = sources(&["10.0.0.0/8", "2001:db8::/32", "sg-0123abcd"]);
+        let value = service_locations(&parsed);
+        assert_eq!(value, "10.0.0.0/8,2001:db8::/32,sg-0123abcd");
+        assert_eq!(parse_service_locations(&value).unwrap(), parsed);
+        assert_eq!(parse_service_locations("").unwrap(), []);
+        assert!(parse_service_locations("10.0.0.0/8,nowhere").is_err());
+
+        let json = serde_json::to_string(&parsed).unwrap();
+        assert_eq!(json, r#"["10.0.0.0/8","2001:db8::/32","sg-0123abcd"]"#);
+        assert_eq!(serde_json::from_str::<Vec<Source>>(&json).unwrap(), parsed);
+        assert!(serde_json::from_str::<Source>(r#""nowhere""#).is_err());
+    }
+
+    #[test]
+    fn recognises_the_whole_internet() {
+        let anywhere = sources(&["0.0.0.0/0", "::/0"]);
+        assert!(anywhere.iter().all(Source::is_anywhere));
+        let restricted = sources(&["10.0.0.0/8", "2001:db8::/32", "sg-0123abcd"]);
+        assert!(!restricted.iter().any(Source::is_anywhere));
+    }
+
+    #[test]
+    fn renders_a_rule_per_port_and_source() {
+        let mappings: Vec<PortMapping> = vec!["443:8443".parse().unwrap(), "9090".parse().unwrap()];
+        let template = render(
+            SETUP_TEMPLATE,
+            &mappings,
+            &sources(&["10.0.0.0/8", "2001:db8::/32", "sg-0123abcd"]),
+        )
+        .unwrap();
+        let rules = ingress(&template);
+        assert_eq!(rules.len(), 1 + 2 * 3);
+        assert_eq!(rules[0]["CidrIp"], json!({ "Ref": "SSHLocation" }));
+        assert_eq!(
+            rules[1],
+            json!({
+                "Description": SERVICE_RULE,
+                "IpProtocol": "tcp",
+                "FromPort": { "Ref": "Port" },
+                "ToPort": { "Ref": "Port" },
+                "CidrIp": "10.0.0.0/8"
+            })
+        );
+        assert_eq!(rules[2]["CidrIpv6"], "2001:db8::/32");
+        assert_eq!(rules[3]["SourceSecurityGroupId"], "sg-0123abcd");
+        assert_eq!(
+            rules[4],
+            json!({
+                "Description": SERVICE_RULE,
+                "IpProtocol": "tcp",
+                "FromPort": 9090,
+                "ToPort": 9090,
+                "CidrIp": "10.0.0.0/8"
+            })
+        );
+    }
+
+    #[test]
+    fn rendering_replaces_previous_service_rules() {
+        let mappings: Vec<PortMapping> = vec!["5000".parse().unwrap()];
+        let open = render(SETUP_TEMPLATE, &mappings, &sources(&["0.0.0.0/0"])).unwrap();
+        let restricted = render(&open, &mappings, &sources(&["sg-0123abcd"])).unwrap();
+        let rules = ingress(&restricted);
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0]["FromPort"], "22");
+        assert_eq!(rules[1]["SourceSecurityGroupId"], "sg-0123abcd");
+    }
+
+    #[test]
+    fn rendering_keeps_rules_added_by_an_overlay() {
+        let mappings: Vec<PortMapping> = vec!["5000".parse().unwrap()];
+        let mut template: Value = serde_json::from_str(SETUP_TEMPLATE).unwrap();
+        let monitoring = json!({
+            "IpProtocol": "tcp",
+            "FromPort": 9100,
+            "ToPort": 9100,
+            "CidrIp": "10.0.0.0/8"
+        });
+        template
+            .pointer_mut(INGRESS)
+            .and_then(Value::as_array_mut)
+            .unwrap()
+            .push(monitoring.clone());
+        let template = serde_json::to_string(&template).unwrap();
+
+        let open = render(&template, &mappings, &sources(&["0.0.0.0/0"])).unwrap();
+        let restricted = render(&open, &mappings, &sources(&["sg-0123abcd"])).unwrap();
+        let rules = ingress(&restricted);
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules[0]["FromPort"], "22");
+        assert_eq!(rules[1], monitoring);
+        assert_eq!(rules[2]["SourceSecurityGroupId"], "sg-0123abcd");
+    }
+
+    #[test]
+    fn rendering_replaces_unmarked_rules_from_older_stacks() {
+        let mappings: Vec<PortMapping> = vec!["5000".parse().unwrap(), "6000".parse().unwrap()];
+        let mut template: Value = serde_json::from_str(SETUP_TEMPLATE).unwrap();
+        let ingress_rules = template
+            .pointer_mut(INGRESS)
+            .and_then(Value::as_array_mut)
+            .unwrap();
+        for port in [json!({ "Ref": "Port" }), json!(6000)] {
+            ingress_rules.push(json!({
+                "IpProtocol": "tcp",
+                "FromPort": port,
+                "ToPort": port,
+                "CidrIp": "0.0.0.0/0"
+            }));
+        }
+        let template = serde_json::to_string(&template).unwrap();
+
+        let rules = ingress(&render(&template, &mappings, &sources(&["sg-0123abcd"])).unwrap());
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules[0]["FromPort"], "22");
+        assert!(rules[1..]
+            .iter()
+            .all(|rule| rule["SourceSecurityGroupId"] == "sg-0123abcd"));
+    }
+
+    #[test]
+    fn rendering_needs_sources_and_a_security_group() {
+        let mappings: Vec<PortMapping> = vec!["5000".parse().unwrap()];
+        assert!(render(SETUP_TEMPLATE, &mappings, &[]).is_err());
+        assert!(render(r#"{"Resources":{}}"#, &mappings, &sources(&["0.0.0.0/0"])).is_err());
+    }
+}
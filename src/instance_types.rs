@@ -0,0 +1,363 @@
+use crate::arch::Arch;
+use crate::sizing::HOST_RESERVED_MIB;
+use failure::Error;
+use serde::Serialize;
+use tracing::warn;
+
+/// An instance family that supports Nitro Enclaves.
+/// https://docs.aws.amazon.com/enclaves/latest/user/nitro-enclave.html#nitro-enclave-reqs
+struct Family {
+    name: &'static str,
+    arch: Arch,
+    threads_per_core: u64,
+    memory_gib_per_vcpu: u64,
+    /// On-demand Linux price in us-east-1, which scales linearly with size.
+    hourly_usd_per_vcpu: f64,
+    /// Sizes with enough vCPUs to leave a core to the host, small to large.
+    sizes: &'static [&'static str],
+}
+
+const SIZES_5: &[&str] = &[
+    "xlarge", "2xlarge", "4xlarge", "8xlarge", "12xlarge", "16xlarge", "24xlarge",
+];
+const SIZES_C5: &[&str] = &[
+    "xlarge", "2xlarge", "4xlarge", "9xlarge", "12xlarge", "18xlarge", "24xlarge",
+];
+const SIZES_6I: &[&str] = &[
+    "xlarge", "2xlarge", "4xlarge", "8xlarge", "12xlarge", "16xlarge", "24xlarge", "32xlarge",
+];
+const SIZES_6A: &[&str] = &[
+    "xlarge", "2xlarge", "4xlarge", "8xlarge", "12xlarge", "16xlarge", "24xlarge", "32xlarge",
+    "48xlarge",
+];
+/// Graviton cores are single threaded, so two vCPUs already leave one for the host.
+const SIZES_GRAVITON: &[&str] = &[
+    "large", "xlarge", "2xlarge", "4xlarge", "8xlarge", "12xlarge", "16xlarge",
+];
+
+const fn x86_64(
+    name: &'static str,
+    memory_gib_per_vcpu: u64,
+    hourly_usd_per_vcpu: f64,
+    sizes: &'static [&'static str],
+) -> Family {
+    Family {
+        name,
+        arch: Arch::X86_64,
+        threads_per_core: 2,
+        memory_gib_per_vcpu,
+        hourly_usd_per_vcpu,
+        sizes,
+    }
+}
+
+const fn aarch64(name: &'static str, memory_gib_per_vcpu: u64, hourly_usd_per_vcpu: f64) -> Family {
+    Family {
+        name,
+        arch: Arch::Aarch64,
+        threads_per_core: 1,
+        memory_gib_per_vcpu,
+        hourly_usd_per_vcpu,
+        sizes: SIZES_GRAVITON,
+    }
+}
+
+const FAMILIES: &[Family] = &[
+    x86_64("c5", 2, 0.0425, SIZES_C5),
+    x86_64("c5a", 2, 0.0385, SIZES_5),
+    x86_64("c6a", 2, 0.03825, SIZES_6A),
+    x86_64("c6i", 2, 0.0425, SIZES_6I),
+    aarch64("c6g", 2, 0.034),
+    aarch64("c7g", 2, 0.03625),
+    x86_64("m5", 4, 0.048, SIZES_5),
+    x86_64("m5a", 4, 0.043, SIZES_5),
+    x86_64("m5n", 4, 0.0595, SIZES_5),
+    x86_64("m6a", 4, 0.0432, SIZES_6A),
+    x86_64("m6i", 4, 0.048, SIZES_6I),
+    aarch64("m6g", 4, 0.0385),
+    aarch64("m7g", 4, 0.0408),
+    x86_64("r5", 8, 0.063, SIZES_5),
+    x86_64("r5a", 8, 0.0565, SIZES_5),
+    x86_64("r6a", 8, 0.0567, SIZES_6A),
+    x86_64("r6i", 8, 0.063, SIZES_6I),
+    aarch64("r6g", 8, 0.0504),
+    aarch64("r7g", 8, 0.05355),
+];
+
+/// Families that do not support Nitro Enclaves, with the architecture to
+/// suggest a replacement in.
+const UNSUPPORTED_FAMILIES: &[(&str, Arch)] = &[
+    ("t2", Arch::X86_64),
+    ("t3", Arch::X86_64),
+    ("t3a", Arch::X86_64),
+    ("t4g", Arch::Aarch64),
+    ("a1", Arch::Aarch64),
+];
+
+const GP2_USD_PER_GIB_MONTH: f64 = 0.10;
+const HOURS_PER_MONTH: f64 = 730.0;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct InstanceType {
+    pub name: String,
+    pub arch: Arch,
+    pub vcpus: u64,
+    pub memory_mib: u64,
+    /// Enclaves are given whole cores, so with hyperthreading CPUs come in pairs.
+    pub threads_per_core: u64,
+    /// Approximate on-demand price in us-east-1; other regions differ.
+    pub hourly_usd: f64,
+}
+
+fn vcpus(size: &str) -> Option<u64> {
+    match size {
+        "medium" => Some(1),
+        "large" => Some(2),
+        "xlarge" => Some(4),
+        size => Some(size.strip_suffix("xlarge")?.parse::<u64>().ok()? * 4),
+    }
+}
+
+impl InstanceType {
+    fn new(family: &Family, size: &str) -> Option<Self> {
+        let vcpus = vcpus(size)?;
+        Some(InstanceType {
+            name: format!("{}.{}", family.name, size),
+            arch: family.arch,
+            vcpus,
+            memory_mib: vcpus * family.memory_gib_per_vcpu * 1024,
+            threads_per_core: family.threads_per_core,
+            hourly_usd: vcpus as f64 * family.hourly_usd_per_vcpu,
+        })
+    }
+
+    /// Every instance type known to run enclaves, smallest first.
+    pub fn all() -> Vec<Self> {
+        let mut types: Vec<Self> = FAMILIES
+            .iter()
+            .flat_map(|family| {
+                family
+                    .sizes
+                    .iter()
+                    .filter_map(move |size| Self::new(family, size))
+            })
+            .collect();
+        types.sort_by_key(|t| (t.memory_mib, t.vcpus));
+        types
+    }
+
+    pub fn lookup(name: &str) -> Result<Self, Error> {
+        match Self::all().into_iter().find(|t| t.name == name) {
+            Some(instance_type) => Ok(instance_type),
+            None => Err(failure::err_msg(format!(
+                "Instance type {} does not support Nitro Enclaves or is not known to nitrogen. \
+                Supported families: {}.",
+                name,
+                FAMILIES
+                    .iter()
+                    .map(|family| family.name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))),
+        }
+    }
+
+    /// As [`InstanceType::lookup`], but only types known not to run enclaves
+    /// are rejected. Others missing from the catalogue, such as new families,
+    /// only warn and leave EC2 and nitro-cli to accept or reject them.
+    pub fn lookup_or_warn(name: &str) -> Result<Option<Self>, Error> {
+        if let Ok(instance_type) = Self::lookup(name) {
+            return Ok(Some(instance_type));
+        }
+        Self::check_supported(name)?;
+        warn!(
+            "Instance type {} is not known to nitrogen, skipping the checks that need it.",
+            name
+        );
+        Ok(None)
+    }
+
+    /// Reject families without Nitro Enclaves support, and sizes of supported
+    /// families too small to leave the host a core.
+    fn check_supported(name: &str) -> Result<(), Error> {
+        let (family, size) = name.split_once('.').unwrap_or((name, ""));
+        let (arch, reason) = match UNSUPPORTED_FAMILIES.iter().find(|(f, _)| *f == family) {
+            Some((_, arch)) => (
+                *arch,
+                format!("the {} family does not support Nitro Enclaves", family),
+            ),
+            None => match (FAMILIES.iter().find(|f| f.name == family), vcpus(size)) {
+                (Some(f), Some(vcpus)) if vcpus < f.threads_per_core * 2 => (
+                    f.arch,
+                    format!(
+                        "it has {} vCPUs and enclaves need at least {} on {}",
+                        vcpus,
+                        f.threads_per_core * 2,
+                        f.arch
+                    ),
+                ),
+                _ => return Ok(()),
+            },
+        };
+        let requested = vcpus(size).unwrap_or(0);
+        let nearest = Self::all()
+            .into_iter()
+            .filter(|t| t.arch == arch && t.vcpus >= requested)
+            .min_by_key(|t| (!t.name.starts_with(&format!("{}.", family)), t.vcpus))
+            .map(|t| format!(" The nearest supported type is {}.", t.name))
+            .unwrap_or_default();
+        Err(failure::err_msg(format!(
+            "Instance type {} cannot run enclaves, {}.{}",
+            name, reason, nearest
+        )))
+    }
+
+    /// Most vCPUs an enclave can take while the host keeps a full core.
+    pub fn max_enclave_cpus(&self) -> u64 {
+        self.vcpus - self.threads_per_core
+    }
+
+    pub fn max_enclave_memory_mib(&self) -> u64 {
+        self.memory_mib.saturating_sub(HOST_RESERVED_MIB)
+    }
+
+    pub fn check_arch(&self, arch: Arch) -> Result<(), Error> {
+        if self.arch != arch {
+            return Err(failure::err_msg(format!(
+                "{} is an {} instance type, not {}.",
+                self.name, self.arch, arch
+            )));
+        }
+        Ok(())
+    }
+
+    // `u64::is_multiple_of` needs Rust 1.87, newer than the toolchains we build with
+    #[allow(unknown_lints, clippy::manual_is_multiple_of)]
+    pub fn check_enclave_cpus(&self, cpu_count: u64) -> Result<(), Error> {
+        if cpu_count == 0 || cpu_count % self.threads_per_core != 0 {
+            return Err(failure::err_msg(format!(
+                "Enclave CPU count must be a multiple of {} on {}, whose cores have {} threads.",
+                self.threads_per_core, self.name, self.threads_per_core
+            )));
+        }
+        if cpu_count > self.max_enclave_cpus() {
+            return Err(failure::err_msg(format!(
+                "{} has {} vCPUs, an enclave can use at most {} so the host keeps a core.",
+                self.name,
+                self.vcpus,
+                self.max_enclave_cpus()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Approximate hourly cost of running this instance with a gp2 root disk.
+    pub fn estimate_hourly_usd(&self, disk_size_gib: usize) -> f64 {
+        self.hourly_usd + disk_size_gib as f64 * GP2_USD_PER_GIB_MONTH / HOURS_PER_MONTH
+    }
+
+    /// Cheapest fitting choice is approximated by the smallest type that can
+    /// host an enclave of this size.
+    pub fn smallest_for(arch: Arch, cpu_count: u64, memory_mib: u64) -> Option<Self> {
+        Self::all().into_iter().find(|t| {
+            t.arch == arch
+                && t.check_enclave_cpus(cpu_count).is_ok()
+                && t.max_enclave_memory_mib() >= memory_mib
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vcpus_follow_the_size_name() {
+        assert_eq!(vcpus("medium"), Some(1));
+        assert_eq!(vcpus("large"), Some(2));
+        assert_eq!(vcpus("xlarge"), Some(4));
+        assert_eq!(vcpus("9xlarge"), Some(36));
+        assert_eq!(vcpus("48xlarge"), Some(192));
+        assert_eq!(vcpus("metal"), None);
+        assert_eq!(vcpus("xxlarge"), None);
+    }
+
+    #[test]
+    fn enclave_cpus_leave_the_host_a_core() {
+        let m5 = InstanceType::lookup("m5.xlarge").unwrap();
+        assert!(m5.check_enclave_cpus(2).is_ok());
+        assert!(m5.check_enclave_cpus(0).is_err());
+        // Hyperthreaded cores can only be given to an enclave whole
+        assert!(m5.check_enclave_cpus(1).is_err());
+        assert!(m5.check_enclave_cpus(3).is_err());
+        assert!(m5.check_enclave_cpus(4).is_err());
+
+        let m6g = InstanceType::lookup("m6g.large").unwrap();
+        assert!(m6g.check_enclave_cpus(1).is_ok());
+        assert!(m6g.check_enclave_cpus(2).is_err());
+    }
+
+    #[test]
+    fn smallest_for_fits_the_enclave() {
+        let smallest = InstanceType::smallest_for(Arch::X86_64, 2, 2048).unwrap();
+        assert_eq!(smallest.name, "c5.xlarge");
+
+        let smallest = InstanceType::smallest_for(Arch::X86_64, 2, 12 * 1024).unwrap();
+        assert_eq!(smallest.arch, Arch::X86_64);
+        assert!(smallest.max_enclave_memory_mib() >= 12 * 1024);
+        assert!(smallest.check_enclave_cpus(2).is_ok());
+
+        let smallest = InstanceType::smallest_for(Arch::Aarch64, 1, 1024).unwrap();
+        assert_eq!(smallest.name, "c6g.large");
+
+        assert!(InstanceType::smallest_for(Arch::X86_64, 1, 1024).is_none());
+        assert!(InstanceType::smallest_for(Arch::Aarch64, 1, 1024 * 1024).is_none());
+    }
+
+    #[test]
+    fn rejects_families_without_enclave_support() {
+        for name in [
+            "t2.xlarge",
+            "t3.large",
+            "t3a.2xlarge",
+            "t4g.medium",
+            "a1.large",
+        ] {
+            let err = InstanceType::lookup_or_warn(name).unwrap_err().to_string();
+            assert!(err.contains("does not support Nitro Enclaves"), "{}", err);
+            assert!(err.contains("nearest supported type"), "{}", err);
+        }
+        let err = InstanceType::lookup_or_warn("t3.2xlarge").unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("nearest supported type is c5.2xlarge."));
+        let err = InstanceType::lookup_or_warn("t4g.large").unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("nearest supported type is c6g.large."));
+    }
+
+    #[test]
+    fn rejects_sizes_too_small_for_an_enclave() {
+        let err = InstanceType::lookup_or_warn("m5.large").unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("nearest supported type is m5.xlarge."));
+        let err = InstanceType::lookup_or_warn("m6g.medium").unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("nearest supported type is m6g.large."));
+    }
+
+    #[test]
+    fn unknown_types_only_warn() {
+        assert!(InstanceType::lookup_or_warn("m9i.xlarge")
+            .unwrap()
+            .is_none());
+        assert!(InstanceType::lookup_or_warn("m5.metal").unwrap().is_none());
+        let known = InstanceType::lookup_or_warn("c6i.2xlarge")
+            .unwrap()
+            .unwrap();
+        assert_eq!(known.vcpus, 8);
+    }
+}
@@ -0,0 +1,194 @@
+use crate::cf_utilities::StackOutputs;
+use crate::state::{check_stack_name, nitrogen_dir};
+use failure::Error;
+use std::fs;
+use std::path::PathBuf;
+use tracing::debug;
+
+/// Location of the nitrogen-managed known_hosts file for a stack.
+pub fn path(stack_name: &str) -> Result<PathBuf, Error> {
+    check_stack_name(stack_name)?;
+    Ok(nitrogen_dir().join("known_hosts").join(stack_name))
+}
+
+/// Pin `host_key` (`<type> <base64>`, as found in `ssh_host_*_key.pub`) for
+/// every address of the stack's instance, replacing any previous pin.
+pub fn pin(stack_name: &str, hosts: &[&str], host_key: &str) -> Result<PathBuf, Error> {
+    let host_key = parse_host_key(host_key)?;
+    let known_hosts = path(stack_name)?;
+    if let Some(dir) = known_hosts.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let contents: String = hosts
+        .iter()
+        .filter(|host| !host.is_empty())
+        .map(|host| format!("{} {}\n", host, host_key))
+        .collect();
+    fs::write(&known_hosts, contents)?;
+    debug!(stack_name, known_hosts = %known_hosts.display(), "Pinned SSH host key.");
+    Ok(known_hosts)
+}
+
+/// Known hosts file to verify the stack's instance against. The host key
+/// published by the stack is authoritative and refreshes any previous pin.
+/// Stacks created before host key pinning publish none, so they need a key
+/// pinned with `nitrogen pin-host-key` first.
+pub fn for_stack(stack_name: &str, outputs: &StackOutputs) -> Result<PathBuf, Error> {
+    let known_hosts = path(stack_name)?;
+    match &outputs.host_key {
+        Some(host_key) => pin(stack_name, &outputs.addresses(), host_key),
+        None if known_hosts.exists() => Ok(known_hosts),
+        None => Err(failure::err_msg(format!(
+            "Stack '{}' does not publish an SSH host key, it was likely created by an older \
+            version of nitrogen. Pin the instance's host key with `nitrogen pin-host-key`, or \
+            recreate the stack with `nitrogen setup`.",
+            stack_name
+        ))),
+    }
+}
+
+/// Forget the pinned host key of a deleted stack.
+pub fn remove(stack_name: &str) -> Result<(), Error> {
+    match fs::remove_file(path(stack_name)?) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Key types pinned for `host`, used to make the server present a key we can check.
+pub(crate) fn key_types(contents: &str, host: &str) -> Vec<String> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let hosts = fields.next()?;
+            let key_type = fields.next()?;
+            hosts
+                .split(',')
+                .any(|h| h == host)
+                .then(|| key_type.to_string())
+        })
+        .collect()
+}
+
+/// Normalise a public key line to `<type> <base64>`, dropping any comment.
+/// The key blob must decode and start with the same type.
+pub(crate) fn parse_host_key(host_key: &str) -> Result<String, Error> {
+    let mut fields = host_key.split_whitespace();
+    let (key_type, key) = match (fields.next(), fields.next()) {
+        (Some(key_type), Some(key))
+            if key_type.starts_with("ssh-") || key_type.starts_with("ecdsa-") =>
+        {
+            (key_type, key)
+        }
+        _ => {
+            return Err(failure::err_msg(format!(
+                "'{}' is not an SSH public host key",
+                host_key
+            )))
+        }
+    };
+    // The blob starts with its type as a length-prefixed string
+    let blob = base64::decode(key).unwrap_or_default();
+    let blob_type = blob.get(..4).and_then(|len| {
+        let len = u32::from_be_bytes(len.try_into().ok()?) as usize;
+        blob.get(4..4usize.checked_add(len)?)
+    });
+    if blob_type != Some(key_type.as_bytes()) {
+        return Err(failure::err_msg(format!(
+            "'{}' is not a valid {} key",
+            host_key, key_type
+        )));
+    }
+    Ok(format!("{} {}", key_type, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state;
+
+    const ED25519: &str = "ssh-ed25519 \
+        AAAAC3NzaC1lZDI1NTE5AAAAIOXGhVQhaOkQCI+3PDOa9lNvWpJplkLAGR4vMWS7INWt root@ip-10-0-0-5";
+    const ECDSA: &str = "ecdsa-sha2-nistp256 \
+        AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBBu6UJlII4GXl5xpgiTR5lzjr/tRoSMArAkxV\
+        dTtof3j/1AWrwGxFffxeJ1KAcn6pbXU5eiwZJ9SgQdEnB6Bhtw= root@ip-10-0-0-5";
+    const RSA: &str = "ssh-rsa \
+        AAAAB3NzaC1yc2EAAAADAQABAAAAgQC5peVqa1cUOF3cDCdwH+TZzAjRIFo/x3TzPkisktePaGbD9aFK4ABVrr4mj\
+        pi9ZGPVCaLVR9i7LdkF1k+KNVvaHHESWaSTp8Gu/GTT5+bWgzItb5PQDnZwHq10CzoNVLUhf0JMiwmQMlQoZPnty\
+        tQgveM3iuPUMAtKpp63wUYt+Q== root@ip-10-0-0-5";
+
+    #[test]
+    fn parses_host_keys_dropping_comments() {
+        for key in [ED25519, ECDSA, RSA] {
+            let parsed = parse_host_key(key).unwrap();
+            assert_eq!(parsed, key.trim_end_matches(" root@ip-10-0-0-5"));
+            assert_eq!(parse_host_key(&parsed).unwrap(), parsed);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_host_keys() {
+        for key in [
+            "",
+            "ssh-ed25519",
+            "AAAAC3NzaC1lZDI1NTE5AAAAIOXGhVQhaOkQCI+3PDOa9lNvWpJplkLAGR4vMWS7INWt",
+            "rsa AAAAB3NzaC1yc2EAAAADAQABAAAAgQC5",
+            "ssh-ed25519 not-base64!",
+            "ssh-ed25519 AAAA",
+            "ssh-ed25519 /////w==",
+        ] {
+            assert!(parse_host_key(key).is_err(), "{}", key);
+        }
+    }
+
+    #[test]
+    fn rejects_keys_whose_type_does_not_match_the_blob() {
+        let rsa_blob = RSA.split_whitespace().nth(1).unwrap();
+        let err = parse_host_key(&format!("ssh-ed25519 {}", rsa_blob)).unwrap_err();
+        assert!(err.to_string().contains("is not a valid ssh-ed25519 key"));
+        let ed25519_blob = ED25519.split_whitespace().nth(1).unwrap();
+        assert!(parse_host_key(&format!("ecdsa-sha2-nistp256 {}", ed25519_blob)).is_err());
+    }
+
+    #[test]
+    fn lists_the_key_types_pinned_for_a_host() {
+        let contents = format!(
+            "ec2-203-0-113-1.compute-1.amazonaws.com,203.0.113.1 {}\n10.0.0.5 {}\n\n\
+            10.0.0.5 {}\nmalformed\n",
+            parse_host_key(ED25519).unwrap(),
+            parse_host_key(ECDSA).unwrap(),
+            parse_host_key(RSA).unwrap(),
+        );
+        assert_eq!(key_types(&contents, "203.0.113.1"), ["ssh-ed25519"]);
+        assert_eq!(
+            key_types(&contents, "ec2-203-0-113-1.compute-1.amazonaws.com"),
+            ["ssh-ed25519"]
+        );
+        assert_eq!(
+            key_types(&contents, "10.0.0.5"),
+            ["ecdsa-sha2-nistp256", "ssh-rsa"]
+        );
+        assert!(key_types(&contents, "203.0.113").is_empty());
+        assert!(key_types(&contents, "malformed").is_empty());
+    }
+
+    #[test]
+    fn pinning_replaces_the_previous_key() {
+        let dir = state::use_temp_dir("known-hosts-pin");
+        let known_hosts = pin("stack", &["10.0.0.5", ""], ED25519).unwrap();
+        pin("stack", &["203.0.113.1", "10.0.0.5"], RSA).unwrap();
+        let contents = fs::read_to_string(&known_hosts).unwrap();
+        assert_eq!(contents.lines().count(), 2);
+        assert_eq!(key_types(&contents, "10.0.0.5"), ["ssh-rsa"]);
+        assert!(pin("stack", &["10.0.0.5"], "ssh-ed25519 AAAA").is_err());
+        assert!(pin("../stack", &["10.0.0.5"], ED25519).is_err());
+
+        remove("stack").unwrap();
+        assert!(!known_hosts.exists());
+        remove("stack").unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
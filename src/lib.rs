@@ -1,3 +1,19 @@
+pub mod arch;
+pub mod attestation;
+pub mod caller_ip;
 pub mod cf_utilities;
 pub mod commands;
+pub mod egress;
+pub mod eif;
+pub mod enclave;
+pub mod ingress;
+pub mod instance_types;
+pub mod known_hosts;
+pub mod manifest;
+pub mod output;
+pub mod overlay;
+pub mod ports;
+pub mod remote;
+pub mod sizing;
+pub mod state;
 pub mod template;
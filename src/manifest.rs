@@ -0,0 +1,136 @@
+use crate::arch::Arch;
+use crate::enclave::Measurements;
+use failure::Error;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Everything needed to reproduce and check an EIF, written next to it as
+/// `<eif>.manifest.json` by `nitrogen build`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BuildManifest {
+    pub eif: String,
+    pub eif_sha384: String,
+    pub measurements: Measurements,
+    #[serde(default)]
+    pub arch: Arch,
+    /// Docker build context directory.
+    pub context: String,
+    pub dockerfile: String,
+    pub docker_image_digest: String,
+    pub builder_image: String,
+    pub builder_image_digest: String,
+    /// Certificate the EIF was signed with, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing_certificate: Option<String>,
+    pub built_at: String,
+}
+
+/// A field whose value changed between two builds.
+#[derive(Clone, Debug, Serialize)]
+pub struct ManifestDifference {
+    pub field: String,
+    pub recorded: String,
+    pub rebuilt: String,
+    /// Whether the field is part of what the enclave is measured by.
+    pub measured: bool,
+}
+
+impl fmt::Display for ManifestDifference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} -> {}", self.field, self.recorded, self.rebuilt)
+    }
+}
+
+impl BuildManifest {
+    pub fn path_for(eif: &Path) -> PathBuf {
+        let mut path = eif.as_os_str().to_owned();
+        path.push(".manifest.json");
+        PathBuf::from(path)
+    }
+
+    pub fn save(&self) -> Result<PathBuf, Error> {
+        let path = Self::path_for(Path::new(&self.eif));
+        fs::write(&path, serde_json::to_string_pretty(self)?)?;
+        Ok(path)
+    }
+
+    pub fn load_for(eif: &Path) -> Result<Self, Error> {
+        let path = Self::path_for(eif);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) => {
+                return Err(failure::err_msg(format!(
+                    "Unable to read build manifest {}: {}. Rebuild the EIF with `nitrogen build`.",
+                    path.display(),
+                    err
+                )))
+            }
+        };
+        match serde_json::from_str(&contents) {
+            Ok(manifest) => Ok(manifest),
+            Err(err) => Err(failure::err_msg(format!(
+                "Unable to parse build manifest {}: {}",
+                path.display(),
+                err
+            ))),
+        }
+    }
+
+    /// Fields that differ from `rebuilt`, ignoring where and when each was built.
+    pub fn diff(&self, rebuilt: &BuildManifest) -> Vec<ManifestDifference> {
+        let optional = |value: &Option<String>| value.clone().unwrap_or_else(|| "-".to_string());
+        let fields = [
+            // The EIF metadata section carries its build time, so the file hash
+            // changes with every build even when the measurements do not
+            ("eif_sha384", &self.eif_sha384, &rebuilt.eif_sha384, false),
+            (
+                "pcr0",
+                &self.measurements.pcr0,
+                &rebuilt.measurements.pcr0,
+                true,
+            ),
+            (
+                "pcr1",
+                &self.measurements.pcr1,
+                &rebuilt.measurements.pcr1,
+                true,
+            ),
+            (
+                "pcr2",
+                &self.measurements.pcr2,
+                &rebuilt.measurements.pcr2,
+                true,
+            ),
+            (
+                "pcr8",
+                &optional(&self.measurements.pcr8),
+                &optional(&rebuilt.measurements.pcr8),
+                true,
+            ),
+            (
+                "docker_image_digest",
+                &self.docker_image_digest,
+                &rebuilt.docker_image_digest,
+                false,
+            ),
+            (
+                "builder_image_digest",
+                &self.builder_image_digest,
+                &rebuilt.builder_image_digest,
+                false,
+            ),
+        ];
+        fields
+            .into_iter()
+            .filter(|(_, recorded, rebuilt, _)| recorded != rebuilt)
+            .map(|(field, recorded, rebuilt, measured)| ManifestDifference {
+                field: field.to_string(),
+                recorded: recorded.to_string(),
+                rebuilt: rebuilt.to_string(),
+                measured,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,260 @@
+use crate::attestation::{AttestationDocument, PcrCheck};
+use crate::cf_utilities::{ResourceChange, StackOutputs};
+use crate::commands::list::{EnclaveSummary, StackSummary};
+use crate::egress::EgressProxy;
+use crate::eif::Eif;
+use crate::enclave::EnclaveDescription;
+use crate::instance_types::InstanceType;
+use crate::manifest::{BuildManifest, ManifestDifference};
+use crate::sizing::MemorySizing;
+use clap::ValueEnum;
+use failure::Error;
+use serde::Serialize;
+use std::fmt;
+
+/// How command results are reported. Tracing always goes to stderr; in `json`
+/// mode a single result document is additionally written to stdout.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl OutputFormat {
+    pub fn is_json(&self) -> bool {
+        *self == OutputFormat::Json
+    }
+
+    /// Write `result` to stdout if machine-readable output was requested.
+    pub fn emit<T: Serialize>(&self, result: &T) -> Result<(), Error> {
+        if self.is_json() {
+            println!("{}", serde_json::to_string_pretty(result)?);
+        }
+        Ok(())
+    }
+
+    /// Write `result` to stdout in either format, for commands whose only
+    /// purpose is to report something.
+    pub fn print<T: Serialize + fmt::Display>(&self, result: &T) -> Result<(), Error> {
+        match self {
+            OutputFormat::Json => self.emit(result),
+            OutputFormat::Text => {
+                println!("{}", result);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SetupOutput {
+    pub name: String,
+    #[serde(flatten)]
+    pub outputs: StackOutputs,
+    pub open_ports: Vec<usize>,
+    pub elapsed_secs: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BuildOutput {
+    #[serde(flatten)]
+    pub manifest: BuildManifest,
+    pub manifest_path: String,
+    pub elapsed_secs: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct VerifyBuildOutput {
+    pub eif: String,
+    pub recorded: BuildManifest,
+    pub rebuilt: BuildManifest,
+    pub differences: Vec<ManifestDifference>,
+    /// The rebuilt EIF is measured identically to the recorded one.
+    pub reproducible: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct InspectOutput {
+    #[serde(flatten)]
+    pub image: Eif,
+    pub memory: MemorySizing,
+    /// Smallest instance type that can run the EIF with the default headroom.
+    pub suggested_instance_type: Option<InstanceType>,
+}
+
+impl fmt::Display for InspectOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.image)?;
+        writeln!(
+            f,
+            "memory:         {} MiB ({})",
+            self.memory.memory_mib, self.memory
+        )?;
+        match &self.suggested_instance_type {
+            Some(instance_type) => write!(
+                f,
+                "instance_type:  {} ({} vCPUs, {} MiB)",
+                instance_type.name, instance_type.vcpus, instance_type.memory_mib
+            ),
+            None => write!(f, "instance_type:  none large enough"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DeployOutput {
+    pub name: String,
+    /// Address the EIF was deployed through, see [`StackOutputs::host`].
+    pub host: String,
+    pub public_dns: Option<String>,
+    pub eif: String,
+    pub enclave: EnclaveDescription,
+    pub memory: MemorySizing,
+    /// Proxies letting the enclave connect out, see `examples/egress`.
+    pub egress: Vec<EgressProxy>,
+    pub elapsed_secs: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LogsOutput {
+    pub name: String,
+    pub host: String,
+    pub enclave: EnclaveDescription,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PinHostKeyOutput {
+    pub name: String,
+    pub known_hosts: String,
+    /// Addresses of the instance the key was pinned for.
+    pub addresses: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AttestOutput {
+    pub name: String,
+    pub enclave_id: String,
+    pub eif: String,
+    pub document: AttestationDocument,
+    pub pcrs: Vec<PcrCheck>,
+    pub verified: bool,
+}
+
+/// What `setup --dry-run` or `delete --dry-run` would do.
+#[derive(Clone, Debug, Serialize)]
+pub struct PlanOutput {
+    pub name: String,
+    pub action: String,
+    pub changes: Vec<ResourceChange>,
+    pub instance_type: Option<String>,
+    /// Approximate on-demand cost of the instance and its disk, in us-east-1.
+    pub estimated_hourly_usd: Option<f64>,
+}
+
+impl fmt::Display for PlanOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Dry run, would {} stack '{}':", self.action, self.name)?;
+        for change in &self.changes {
+            writeln!(
+                f,
+                "  {:<8} {:<28} {}{}",
+                change.action,
+                change.logical_resource_id,
+                change.resource_type,
+                if change.is_replaced() {
+                    " (replaced)"
+                } else {
+                    ""
+                }
+            )?;
+        }
+        match (&self.instance_type, self.estimated_hourly_usd) {
+            (Some(instance_type), Some(hourly)) => write!(
+                f,
+                "Estimated {}: ${:.4}/hour, ${:.2}/month ({}, on-demand us-east-1)",
+                if self.action == "delete" {
+                    "savings"
+                } else {
+                    "cost"
+                },
+                hourly,
+                hourly * 730.0,
+                instance_type
+            ),
+            _ => write!(f, "Estimated cost: unknown"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct UpdateOutput {
+    pub name: String,
+    pub changes: Vec<ResourceChange>,
+    /// Whether the change set was executed, `false` when there was nothing to change.
+    pub executed: bool,
+    #[serde(flatten)]
+    pub outputs: StackOutputs,
+    pub elapsed_secs: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DeleteOutput {
+    pub name: String,
+    pub stack_id: String,
+    pub elapsed_secs: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StartOutput {
+    pub service: String,
+    pub setup: SetupOutput,
+    pub build: BuildOutput,
+    pub deploy: DeployOutput,
+    pub elapsed_secs: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(transparent)]
+pub struct ListOutput {
+    pub stacks: Vec<StackSummary>,
+}
+
+impl fmt::Display for ListOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<24} {:<20} {:<14} {:<48} {:<22} ENCLAVE",
+            "NAME", "STATUS", "INSTANCE_TYPE", "PUBLIC_DNS", "CREATED"
+        )?;
+        for stack in &self.stacks {
+            let enclave = match &stack.enclave {
+                EnclaveSummary::Running(enclave) => format!(
+                    "{} {} ({} CPUs, {} MiB{})",
+                    enclave.state,
+                    enclave.enclave_id,
+                    enclave.cpu_count,
+                    enclave.memory_mib,
+                    if enclave.is_debug_mode() {
+                        ", debug"
+                    } else {
+                        ""
+                    }
+                ),
+                EnclaveSummary::NotRunning => "none".to_string(),
+                EnclaveSummary::Unknown { reason } => format!("unknown ({})", reason),
+            };
+            write!(
+                f,
+                "\n{:<24} {:<20} {:<14} {:<48} {:<22} {}",
+                stack.name,
+                stack.status,
+                stack.instance_type.as_deref().unwrap_or("-"),
+                stack.public_dns.as_deref().unwrap_or("-"),
+                stack.created.as_deref().unwrap_or("-"),
+                enclave
+            )?;
+        }
+        Ok(())
+    }
+}
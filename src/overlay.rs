@@ -0,0 +1,327 @@
+use failure::Error;
+use serde_json::{Map, Value};
+use std::fs;
+use std::path::Path;
+
+/// Outputs nitrogen reads back from every stack it creates.
+/// `PublicDNS` and `PublicIP` are left out as private instances have neither.
+pub const REQUIRED_OUTPUTS: &[&str] = &["InstanceId", "AZ", "PrivateIP", "HostKey"];
+/// Parameters `setup` always passes, which CloudFormation rejects unless the
+/// template declares them.
+pub const REQUIRED_PARAMETERS: &[&str] = &[
+    "InstanceName",
+    "InstanceType",
+    "DiskSize",
+    "Port",
+    "PublicKey",
+    "SSHLocation",
+    "LatestAmiId",
+    "VpcId",
+    "SubnetId",
+    "AssociatePublicIp",
+    "VsockPort",
+    "ExtraPortMappings",
+    "ServiceLocations",
+];
+
+/// Read an overlay file, as YAML if it has a `.yaml` or `.yml` extension and
+/// as JSON otherwise.
+pub fn load(path: &Path) -> Result<Value, Error> {
+    let contents = fs::read_to_string(path).map_err(|err| {
+        failure::err_msg(format!(
+            "Unable to read template overlay {}: {}",
+            path.display(),
+            err
+        ))
+    })?;
+    let overlay = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("yaml" | "yml") => serde_yaml::from_str(&contents)
+            .map_err(Error::from)
+            .and_then(from_yaml),
+        _ => serde_json::from_str(&contents).map_err(Error::from),
+    };
+    match overlay {
+        Ok(overlay @ Value::Object(_)) => Ok(overlay),
+        Ok(_) => Err(failure::err_msg(format!(
+            "Template overlay {} must be an object.",
+            path.display()
+        ))),
+        Err(err) => Err(failure::err_msg(format!(
+            "Unable to parse template overlay {}: {}",
+            path.display(),
+            err
+        ))),
+    }
+}
+
+/// Convert a YAML overlay to JSON, expanding the short form of intrinsic
+/// functions such as `!Ref` and `!GetAtt` to the long form JSON templates use.
+fn from_yaml(value: serde_yaml::Value) -> Result<Value, Error> {
+    use serde_yaml::Value as Yaml;
+    Ok(match value {
+        Yaml::Null => Value::Null,
+        Yaml::Bool(b) => Value::Bool(b),
+        Yaml::Number(n) => serde_json::to_value(n)?,
+        Yaml::String(s) => Value::String(s),
+        Yaml::Sequence(items) => {
+            Value::Array(items.into_iter().map(from_yaml).collect::<Result<_, _>>()?)
+        }
+        Yaml::Mapping(mapping) => {
+            let mut object = Map::new();
+            for (key, value) in mapping {
+                let key = match key {
+                    Yaml::String(key) => key,
+                    Yaml::Number(n) => n.to_string(),
+                    Yaml::Bool(b) => b.to_string(),
+                    key => {
+                        return Err(failure::err_msg(format!(
+                            "Keys must be strings, not {:?}.",
+                            key
+                        )))
+                    }
+                };
+                object.insert(key, from_yaml(value)?);
+            }
+            Value::Object(object)
+        }
+        Yaml::Tagged(tagged) => {
+            let function = tagged.tag.to_string();
+            let function = function.trim_start_matches('!');
+            let value = match (function, from_yaml(tagged.value)?) {
+                // `!GetAtt Resource.Attribute` is the short form of a list
+                ("GetAtt", Value::String(s)) => match s.split_once('.') {
+                    Some((resource, attribute)) => serde_json::json!([resource, attribute]),
+                    None => Value::String(s),
+                },
+                (_, value) => value,
+            };
+            let key = match function {
+                "Ref" | "Condition" => function.to_string(),
+                _ => format!("Fn::{}", function),
+            };
+            let mut object = Map::new();
+            object.insert(key, value);
+            Value::Object(object)
+        }
+    })
+}
+
+/// Merge `overlay` into `base` following JSON Merge Patch (RFC 7386): objects
+/// are merged key by key, `null` removes a key and anything else replaces it.
+pub fn merge(base: &mut Value, overlay: Value) {
+    let overlay = match overlay {
+        Value::Object(overlay) => overlay,
+        overlay => {
+            *base = overlay;
+            return;
+        }
+    };
+    // An object replaces anything else, nulls inside it still remove keys
+    if !base.is_object() {
+        *base = Value::Object(Map::new());
+    }
+    if let Value::Object(base) = base {
+        for (key, value) in overlay {
+            if value.is_null() {
+                base.remove(&key);
+            } else {
+                merge(base.entry(key).or_insert(Value::Null), value);
+            }
+        }
+    }
+}
+
+/// Check a template still declares what `setup` and the other commands rely on.
+pub fn validate(template: &Value) -> Result<(), Error> {
+    let missing = |section: &str, keys: &[&str]| -> Vec<String> {
+        keys.iter()
+            .filter(|key| template[section].get(**key).is_none())
+            .map(|key| format!("{}.{}", section, key))
+            .collect()
+    };
+    let mut missing_keys = missing("Parameters", REQUIRED_PARAMETERS);
+    missing_keys.extend(missing("Outputs", REQUIRED_OUTPUTS));
+    if template["Resources"].get("EC2Instance").is_none() {
+        missing_keys.push("Resources.EC2Instance".to_string());
+    }
+    if !missing_keys.is_empty() {
+        return Err(failure::err_msg(format!(
+            "The template is missing {}, which nitrogen needs.",
+            missing_keys.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// The embedded `template` with the overlay at `path` merged in.
+pub fn apply(template: &str, path: &Path) -> Result<String, Error> {
+    let mut merged: Value = serde_json::from_str(template)?;
+    merge(&mut merged, load(path)?);
+    validate(&merged)?;
+    Ok(serde_json::to_string(&merged)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template::SETUP_TEMPLATE;
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    fn write(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn merges_rfc7386_examples() {
+        // Appendix A of RFC 7386
+        let examples = [
+            (json!({"a": "b"}), json!({"a": "c"}), json!({"a": "c"})),
+            (
+                json!({"a": "b"}),
+                json!({"b": "c"}),
+                json!({"a": "b", "b": "c"}),
+            ),
+            (json!({"a": "b"}), json!({"a": null}), json!({})),
+            (
+                json!({"a": "b", "b": "c"}),
+                json!({"a": null}),
+                json!({"b": "c"}),
+            ),
+            (json!({"a": ["b"]}), json!({"a": "c"}), json!({"a": "c"})),
+            (json!({"a": "c"}), json!({"a": ["b"]}), json!({"a": ["b"]})),
+            (
+                json!({"a": {"b": "c"}}),
+                json!({"a": {"b": "d", "c": null}}),
+                json!({"a": {"b": "d"}}),
+            ),
+            (
+                json!({"a": [{"b": "c"}]}),
+                json!({"a": [1]}),
+                json!({"a": [1]}),
+            ),
+            (json!(["a", "b"]), json!(["c", "d"]), json!(["c", "d"])),
+            (json!({"a": "b"}), json!(["c"]), json!(["c"])),
+            (json!({"a": "foo"}), json!(null), json!(null)),
+            (json!({"a": "foo"}), json!("bar"), json!("bar")),
+            (
+                json!({"e": null}),
+                json!({"a": 1}),
+                json!({"e": null, "a": 1}),
+            ),
+            (
+                json!([1, 2]),
+                json!({"a": "b", "c": null}),
+                json!({"a": "b"}),
+            ),
+            (
+                json!({}),
+                json!({"a": {"bb": {"ccc": null}}}),
+                json!({"a": {"bb": {}}}),
+            ),
+        ];
+        for (mut base, overlay, expected) in examples {
+            let patch = overlay.to_string();
+            merge(&mut base, overlay);
+            assert_eq!(base, expected, "{}", patch);
+        }
+    }
+
+    #[test]
+    fn the_setup_template_is_valid() {
+        let template: Value = serde_json::from_str(SETUP_TEMPLATE).unwrap();
+        validate(&template).unwrap();
+    }
+
+    #[test]
+    fn validate_lists_what_is_missing() {
+        let mut template: Value = serde_json::from_str(SETUP_TEMPLATE).unwrap();
+        merge(
+            &mut template,
+            json!({
+                "Parameters": {"VsockPort": null},
+                "Outputs": {"HostKey": null},
+                "Resources": {"EC2Instance": null}
+            }),
+        );
+        let err = validate(&template).unwrap_err().to_string();
+        assert!(err.contains("Parameters.VsockPort"), "{}", err);
+        assert!(err.contains("Outputs.HostKey"), "{}", err);
+        assert!(err.contains("Resources.EC2Instance"), "{}", err);
+    }
+
+    #[test]
+    fn applies_an_overlay_file() {
+        let path = write(
+            "overlay.json",
+            r#"{"Resources": {"EC2Instance": {"Properties": {"IamInstanceProfile": "enclave"}}}}"#,
+        );
+        let merged: Value = serde_json::from_str(&apply(SETUP_TEMPLATE, &path).unwrap()).unwrap();
+        fs::remove_file(path).unwrap();
+        let instance = &merged["Resources"]["EC2Instance"]["Properties"];
+        assert_eq!(instance["IamInstanceProfile"], "enclave");
+        assert_eq!(instance["InstanceType"], json!({"Ref": "InstanceType"}));
+    }
+
+    #[test]
+    fn applies_a_yaml_overlay_file() {
+        let path = write(
+            "overlay.yaml",
+            r#"
+Resources:
+  EC2Instance:
+    Properties:
+      IamInstanceProfile: enclave
+      Tags:
+        - Key: Name
+          Value: !Ref InstanceName
+        - Key: az
+          Value: !GetAtt EC2Instance.AvailabilityZone
+        - Key: host
+          Value: !Sub '${InstanceName}.internal'
+      KeyName: null
+"#,
+        );
+        let merged: Value = serde_json::from_str(&apply(SETUP_TEMPLATE, &path).unwrap()).unwrap();
+        fs::remove_file(path).unwrap();
+        let instance = &merged["Resources"]["EC2Instance"]["Properties"];
+        assert_eq!(instance["IamInstanceProfile"], "enclave");
+        assert_eq!(instance["InstanceType"], json!({"Ref": "InstanceType"}));
+        assert!(instance.get("KeyName").is_none());
+        assert_eq!(
+            instance["Tags"],
+            json!([
+                {"Key": "Name", "Value": {"Ref": "InstanceName"}},
+                {"Key": "az", "Value": {"Fn::GetAtt": ["EC2Instance", "AvailabilityZone"]}},
+                {"Key": "host", "Value": {"Fn::Sub": "${InstanceName}.internal"}}
+            ])
+        );
+    }
+
+    #[test]
+    fn merges_yaml_like_json() {
+        let yaml: serde_yaml::Value =
+            serde_yaml::from_str("a: {b: d, c: null}\nn: [1, 2.5, true]").unwrap();
+        let mut base = json!({"a": {"b": "c", "c": 1}, "n": "x"});
+        merge(&mut base, from_yaml(yaml).unwrap());
+        assert_eq!(base, json!({"a": {"b": "d"}, "n": [1, 2.5, true]}));
+    }
+
+    #[test]
+    fn rejects_overlays_that_are_not_objects() {
+        for (name, contents) in [
+            ("array.yaml", "- a"),
+            ("empty.yml", ""),
+            ("broken.yaml", "a: [b"),
+            ("array.json", "[]"),
+            ("broken.json", "{"),
+        ] {
+            let path = write(name, contents);
+            let result = load(&path);
+            fs::remove_file(path).unwrap();
+            assert!(result.is_err(), "{}", name);
+        }
+    }
+}
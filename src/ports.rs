@@ -0,0 +1,171 @@
+use failure::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+/// Vsock port the enclave application listens on unless a mapping says otherwise.
+pub const DEFAULT_VSOCK_PORT: u32 = 5000;
+const SSH_PORT: u16 = 22;
+
+/// A TCP port opened on the instance and forwarded by socat to a vsock port
+/// of the enclave, written `host[:vsock]`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PortMapping {
+    pub host: u16,
+    pub vsock: u32,
+}
+
+impl FromStr for PortMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("'{}' is not a port mapping of the form host[:vsock]", s);
+        let (host, vsock) = match s.split_once(':') {
+            Some((host, vsock)) => (host, vsock.parse().map_err(|_| invalid())?),
+            None => (s, DEFAULT_VSOCK_PORT),
+        };
+        let host = host.parse().map_err(|_| invalid())?;
+        // Neither socat nor the security group can forward port 0
+        if host == 0 || vsock == 0 {
+            return Err(invalid());
+        }
+        Ok(PortMapping { host, vsock })
+    }
+}
+
+impl fmt::Display for PortMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.host, self.vsock)
+    }
+}
+
+/// Check the mappings can all be forwarded from the same instance.
+pub fn check(mappings: &[PortMapping]) -> Result<(), Error> {
+    if mappings.is_empty() {
+        return Err(failure::err_msg("At least one port mapping is needed."));
+    }
+    let mut seen = HashSet::new();
+    for mapping in mappings {
+        if mapping.host == SSH_PORT {
+            return Err(failure::err_msg(format!(
+                "Port {} is reserved for SSH.",
+                SSH_PORT
+            )));
+        }
+        if !seen.insert(mapping.host) {
+            return Err(failure::err_msg(format!(
+                "Port {} is mapped more than once.",
+                mapping.host
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Value of the `ExtraPortMappings` template parameter: every mapping after
+/// the first, which is passed as `Port` and `VsockPort`.
+pub fn extra_mappings(mappings: &[PortMapping]) -> String {
+    mappings
+        .iter()
+        .skip(1)
+        .map(PortMapping::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Mappings of an existing stack, from its `Port`, `VsockPort` and
+/// `ExtraPortMappings` parameters.
+pub fn from_parameters(
+    port: &str,
+    vsock_port: &str,
+    extra_mappings: &str,
+) -> Result<Vec<PortMapping>, Error> {
+    std::iter::once(format!("{}:{}", port, vsock_port))
+        .chain(
+            extra_mappings
+                .split(',')
+                .filter(|mapping| !mapping.is_empty())
+                .map(str::to_string),
+        )
+        .map(|mapping| mapping.parse().map_err(failure::err_msg))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(host: u16, vsock: u32) -> PortMapping {
+        PortMapping { host, vsock }
+    }
+
+    #[test]
+    fn parses_host_and_vsock_ports() {
+        assert_eq!("443:8443".parse(), Ok(mapping(443, 8443)));
+        assert_eq!("8080".parse(), Ok(mapping(8080, DEFAULT_VSOCK_PORT)));
+        assert_eq!("65535:4294967295".parse(), Ok(mapping(65535, u32::MAX)));
+    }
+
+    #[test]
+    fn rejects_malformed_mappings() {
+        for s in [
+            "",
+            ":",
+            "443:",
+            ":8443",
+            "http",
+            "65536",
+            "443:8443:1",
+            "-1",
+            "0",
+            "0:5000",
+            "443:0",
+        ] {
+            assert_eq!(
+                s.parse::<PortMapping>(),
+                Err(format!(
+                    "'{}' is not a port mapping of the form host[:vsock]",
+                    s
+                )),
+                "{}",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn displays_as_it_parses() {
+        let mappings = [mapping(443, 8443), mapping(9090, 9090)];
+        for mapping in mappings {
+            assert_eq!(mapping.to_string().parse(), Ok(mapping));
+        }
+    }
+
+    #[test]
+    fn checks_mappings_fit_one_instance() {
+        assert!(check(&[mapping(443, 8443), mapping(9090, 9090)]).is_ok());
+        // Two host ports may forward to the same vsock port
+        assert!(check(&[mapping(80, 5000), mapping(443, 5000)]).is_ok());
+        assert!(check(&[]).is_err());
+        assert!(check(&[mapping(22, 5000)]).is_err());
+        assert!(check(&[mapping(443, 8443), mapping(443, 9090)]).is_err());
+    }
+
+    #[test]
+    fn round_trips_template_parameters() {
+        let mappings = [mapping(443, 8443), mapping(9090, 9090), mapping(80, 5000)];
+        assert_eq!(extra_mappings(&mappings), "9090:9090,80:5000");
+        assert_eq!(extra_mappings(&mappings[..1]), "");
+        assert_eq!(
+            from_parameters("443", "8443", &extra_mappings(&mappings)).unwrap(),
+            mappings
+        );
+        assert_eq!(
+            from_parameters("5000", "5000", "").unwrap(),
+            [mapping(5000, 5000)]
+        );
+        assert!(from_parameters("443", "8443", "9090").is_ok());
+        assert!(from_parameters("443", "8443", "nine").is_err());
+    }
+}
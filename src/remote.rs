@@ -0,0 +1,469 @@
+use crate::known_hosts;
+use failure::Error;
+use ssh2::{Channel, CheckResult, KnownHostFileKind, MethodType, Session};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// Login user of the Amazon Linux AMI used by the setup template.
+pub const DEFAULT_USER: &str = "ec2-user";
+
+const SSH_PORT: u16 = 22;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+const CHUNK_SIZE: usize = 64 * 1024;
+const READY_INTERVAL: Duration = Duration::from_secs(5);
+/// Succeeds once the host can run enclaves: nitro-cli is installed and the
+/// allocator has reserved memory and CPUs.
+const READY_CHECK: &str =
+    "command -v nitro-cli && systemctl is-active --quiet nitro-enclaves-allocator.service";
+
+/// A remote command ran to completion but exited with a non-zero status.
+#[derive(Debug)]
+pub struct ExitError {
+    pub host: String,
+    pub command: String,
+    pub exit_status: i32,
+    pub stderr: String,
+}
+
+impl fmt::Display for ExitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}` on {} exited with status {}",
+            self.command, self.host, self.exit_status
+        )?;
+        if !self.stderr.trim().is_empty() {
+            write!(f, ": {}", self.stderr.trim())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ExitError {}
+
+/// Captured output of a successful remote command.
+#[derive(Debug)]
+pub struct ExecOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// An authenticated SSH connection to an enclave host. Every remote step of a
+/// command goes through a single `RemoteHost` rather than one process per call.
+pub struct RemoteHost {
+    host: String,
+    session: Session,
+}
+
+impl RemoteHost {
+    /// Connect to `host` as `ec2-user`, authenticating with the private key at
+    /// `ssh_key`. The host must present a key pinned in the `known_hosts` file.
+    pub fn connect(host: &str, ssh_key: &str, known_hosts: &Path) -> Result<Self, Error> {
+        Self::connect_as(host, DEFAULT_USER, ssh_key, known_hosts)
+    }
+
+    fn connect_as(
+        host: &str,
+        user: &str,
+        ssh_key: &str,
+        known_hosts: &Path,
+    ) -> Result<Self, Error> {
+        let pinned = match fs::read_to_string(known_hosts) {
+            Ok(pinned) => pinned,
+            Err(err) => {
+                return Err(failure::err_msg(format!(
+                    "unable to read known hosts file {}: {}",
+                    known_hosts.display(),
+                    err
+                )))
+            }
+        };
+        let key_types = known_hosts::key_types(&pinned, host);
+        if key_types.is_empty() {
+            return Err(failure::err_msg(format!(
+                "no host key pinned for {} in {}",
+                host,
+                known_hosts.display()
+            )));
+        }
+
+        let addr = match (host, SSH_PORT).to_socket_addrs()?.next() {
+            Some(addr) => addr,
+            None => return Err(failure::err_msg(format!("could not resolve {}", host))),
+        };
+        let tcp = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+
+        let mut session = Session::new()?;
+        session.set_tcp_stream(tcp);
+        // Only negotiate key types we have a pin for, so the check below is meaningful
+        session.method_pref(MethodType::HostKey, &key_types.join(","))?;
+        session.handshake()?;
+        verify_host_key(&session, host, known_hosts)?;
+
+        session.userauth_pubkey_file(user, None, Path::new(ssh_key), None)?;
+        if !session.authenticated() {
+            return Err(failure::err_msg(format!(
+                "SSH authentication to {}@{} failed with key {}",
+                user, host, ssh_key
+            )));
+        }
+
+        Ok(RemoteHost {
+            host: host.to_string(),
+            session,
+        })
+    }
+
+    /// Connect as [`RemoteHost::connect`] does, retrying until the instance
+    /// accepts SSH and can run enclaves, or `timeout` passes. A host key that
+    /// does not match the pin fails immediately.
+    pub fn connect_when_ready(
+        host: &str,
+        ssh_key: &str,
+        known_hosts: &Path,
+        timeout: Duration,
+    ) -> Result<Self, Error> {
+        let started = Instant::now();
+        loop {
+            let attempt = Self::connect(host, ssh_key, known_hosts).and_then(|remote| {
+                remote.exec(READY_CHECK)?;
+                Ok(remote)
+            });
+            let err = match attempt {
+                Ok(remote) => return Ok(remote),
+                Err(err) => err,
+            };
+            if !is_transient(&err) {
+                return Err(err);
+            }
+            if started.elapsed() >= timeout {
+                return Err(failure::err_msg(format!(
+                    "{} was not ready after {}s: {}",
+                    host,
+                    timeout.as_secs(),
+                    err
+                )));
+            }
+            info!(host, reason = %err, "Waiting for instance to become ready...");
+            thread::sleep(READY_INTERVAL);
+        }
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Run `command` and collect its output, failing with an [`ExitError`] on a
+    /// non-zero exit status.
+    pub fn exec(&self, command: &str) -> Result<ExecOutput, Error> {
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let exit_status = self.run(command, &mut stdout, &mut stderr)?;
+        check_exit(&self.host, command, exit_status, &stderr)?;
+        Ok(ExecOutput { stdout, stderr })
+    }
+
+    /// Run `command`, forwarding its stdout and stderr to the given writers as
+    /// it is produced.
+    pub fn exec_streamed(
+        &self,
+        command: &str,
+        stdout: &mut dyn Write,
+        stderr: &mut dyn Write,
+    ) -> Result<(), Error> {
+        // Keep the tail of stderr around so failures are still self-explanatory
+        let mut tee = TailWriter::new(stderr);
+        let exit_status = self.run(command, stdout, &mut tee)?;
+        Ok(check_exit(&self.host, command, exit_status, &tee.tail)?)
+    }
+
+    /// Copy `local` to `remote` over SFTP. `progress` is called with the number
+    /// of bytes written so far and the total size of the file.
+    pub fn upload(
+        &self,
+        local: &Path,
+        remote: &str,
+        mut progress: impl FnMut(u64, u64),
+    ) -> Result<(), Error> {
+        let mut source = File::open(local)?;
+        let total = source.metadata()?.len();
+        let sftp = self.session.sftp()?;
+        let mut dest = sftp.create(Path::new(remote))?;
+
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut written = 0;
+        loop {
+            let n = source.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            dest.write_all(&buf[..n])?;
+            written += n as u64;
+            progress(written, total);
+        }
+        dest.fsync().ok();
+        Ok(())
+    }
+
+    fn run(
+        &self,
+        command: &str,
+        stdout: &mut dyn Write,
+        stderr: &mut dyn Write,
+    ) -> Result<i32, Error> {
+        debug!(host = self.host, command, "Running remote command.");
+        let mut channel = self.session.channel_session()?;
+        channel.exec(command)?;
+
+        self.session.set_blocking(false);
+        let pumped = pump(&mut channel, stdout, stderr);
+        self.session.set_blocking(true);
+        pumped?;
+
+        channel.wait_close()?;
+        let exit_status = channel.exit_status()?;
+        debug!(host = self.host, command, exit_status);
+        Ok(exit_status)
+    }
+}
+
+/// Network, SSH and remote exit errors are transient while an instance boots,
+/// anything else is a configuration problem.
+fn is_transient(err: &Error) -> bool {
+    err.downcast_ref::<std::io::Error>().is_some()
+        || err.downcast_ref::<ssh2::Error>().is_some()
+        || err.downcast_ref::<ExitError>().is_some()
+}
+
+fn check_exit(host: &str, command: &str, exit_status: i32, stderr: &[u8]) -> Result<(), ExitError> {
+    match exit_status {
+        0 => Ok(()),
+        exit_status => Err(ExitError {
+            host: host.to_string(),
+            command: command.to_string(),
+            exit_status,
+            stderr: String::from_utf8_lossy(stderr).into_owned(),
+        }),
+    }
+}
+
+/// Drain both output streams of `channel` until the remote side closes them.
+/// The session must be non-blocking so neither stream can starve the other.
+fn pump(
+    channel: &mut Channel,
+    stdout: &mut dyn Write,
+    stderr: &mut dyn Write,
+) -> Result<(), Error> {
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let mut progressed = false;
+        progressed |= forward(channel, &mut buf, stdout)?;
+        progressed |= forward(&mut channel.stderr(), &mut buf, stderr)?;
+        if !progressed {
+            if channel.eof() {
+                return Ok(());
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+fn forward(source: &mut impl Read, buf: &mut [u8], sink: &mut dyn Write) -> Result<bool, Error> {
+    match source.read(buf) {
+        Ok(0) => Ok(false),
+        Ok(n) => {
+            sink.write_all(&buf[..n])?;
+            sink.flush()?;
+            Ok(true)
+        }
+        Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Forwards writes while remembering the last few KiB written.
+struct TailWriter<'a> {
+    inner: &'a mut dyn Write,
+    tail: Vec<u8>,
+}
+
+impl<'a> TailWriter<'a> {
+    const LIMIT: usize = 4 * 1024;
+
+    fn new(inner: &'a mut dyn Write) -> Self {
+        TailWriter {
+            inner,
+            tail: Vec::new(),
+        }
+    }
+}
+
+impl Write for TailWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.tail.extend_from_slice(&buf[..n]);
+        if self.tail.len() > Self::LIMIT {
+            self.tail.drain(..self.tail.len() - Self::LIMIT);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn verify_host_key(session: &Session, host: &str, known_hosts: &Path) -> Result<(), Error> {
+    let key = match session.host_key() {
+        Some((key, _)) => key,
+        None => return Err(failure::err_msg(format!("{} sent no host key", host))),
+    };
+    let mut known = session.known_hosts()?;
+    known.read_file(known_hosts, KnownHostFileKind::OpenSSH)?;
+
+    match known.check(host, key) {
+        CheckResult::Match => {
+            debug!(host, "SSH host key matches pinned key.");
+            Ok(())
+        }
+        CheckResult::Mismatch => Err(failure::err_msg(format!(
+            "host key presented by {} does not match the key pinned in {}, refusing to connect",
+            host,
+            known_hosts.display()
+        ))),
+        CheckResult::NotFound | CheckResult::Failure => Err(failure::err_msg(format!(
+            "unable to verify the host key of {} against {}",
+            host,
+            known_hosts.display()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Cursor};
+
+    #[test]
+    fn zero_exit_status_succeeds() {
+        assert!(check_exit("host", "true", 0, b"").is_ok());
+        assert!(check_exit("host", "true", 0, b"warning").is_ok());
+    }
+
+    #[test]
+    fn non_zero_exit_status_fails_with_stderr() {
+        let err = check_exit("host", "false", 1, b"  it broke\n").unwrap_err();
+        assert_eq!(err.exit_status, 1);
+        assert_eq!(
+            err.to_string(),
+            "`false` on host exited with status 1: it broke"
+        );
+        let err = check_exit("host", "exit 255", 255, b"\n").unwrap_err();
+        assert_eq!(err.to_string(), "`exit 255` on host exited with status 255");
+        // A signal leaves no exit status, which ssh2 reports as -1
+        assert!(check_exit("host", "kill $$", -1, b"").is_err());
+    }
+
+    #[test]
+    fn only_connection_and_exit_errors_are_transient() {
+        let exit: Error = check_exit("host", "false", 1, b"").unwrap_err().into();
+        assert!(is_transient(&exit));
+        let refused: Error = io::Error::from(ErrorKind::ConnectionRefused).into();
+        assert!(is_transient(&refused));
+        assert!(!is_transient(&failure::err_msg("no host key pinned")));
+    }
+
+    struct WouldBlock;
+
+    impl Read for WouldBlock {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(ErrorKind::WouldBlock.into())
+        }
+    }
+
+    #[test]
+    fn forwards_available_output() {
+        let mut buf = vec![0; 4];
+        let mut sink = Vec::new();
+        let mut source = Cursor::new(b"hello".to_vec());
+        assert!(forward(&mut source, &mut buf, &mut sink).unwrap());
+        assert!(forward(&mut source, &mut buf, &mut sink).unwrap());
+        assert!(!forward(&mut source, &mut buf, &mut sink).unwrap());
+        assert_eq!(sink, b"hello");
+        assert!(!forward(&mut WouldBlock, &mut buf, &mut sink).unwrap());
+    }
+
+    #[test]
+    fn tail_writer_keeps_the_end_of_stderr() {
+        let mut inner = Vec::new();
+        let mut tee = TailWriter::new(&mut inner);
+        tee.write_all(&vec![b'a'; TailWriter::LIMIT]).unwrap();
+        tee.write_all(b"the end").unwrap();
+        assert_eq!(tee.tail.len(), TailWriter::LIMIT);
+        assert!(tee.tail.ends_with(b"athe end"));
+        assert_eq!(inner.len(), TailWriter::LIMIT + 7);
+    }
+
+    /// Runs against an SSH server on localhost. The key at
+    /// `NITROGEN_TEST_SSH_KEY` must be authorized for `NITROGEN_TEST_SSH_USER`
+    /// (default `$USER`), and localhost's host key pinned in
+    /// `NITROGEN_TEST_KNOWN_HOSTS` (default `~/.ssh/known_hosts`):
+    /// `NITROGEN_TEST_SSH_KEY=~/.ssh/id_ed25519 cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn uploads_and_runs_commands_over_ssh() {
+        let ssh_key = std::env::var("NITROGEN_TEST_SSH_KEY")
+            .expect("NITROGEN_TEST_SSH_KEY names a key authorized on localhost");
+        let user = std::env::var("NITROGEN_TEST_SSH_USER")
+            .or_else(|_| std::env::var("USER"))
+            .unwrap();
+        let known_hosts = std::env::var("NITROGEN_TEST_KNOWN_HOSTS")
+            .map(std::path::PathBuf::from)
+            .unwrap_or_else(|_| home::home_dir().unwrap().join(".ssh/known_hosts"));
+        let remote = RemoteHost::connect_as("localhost", &user, &ssh_key, &known_hosts).unwrap();
+
+        let local = std::env::temp_dir().join(format!("{}-upload", std::process::id()));
+        let uploaded = format!("{}.uploaded", local.display());
+        let contents = vec![7u8; CHUNK_SIZE * 2 + 1];
+        fs::write(&local, &contents).unwrap();
+        let mut reported = 0;
+        remote
+            .upload(&local, &uploaded, |written, total| {
+                assert_eq!(total, contents.len() as u64);
+                reported = written;
+            })
+            .unwrap();
+        assert_eq!(reported, contents.len() as u64);
+        let size = remote.exec(&format!("wc -c < {}", uploaded)).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&size.stdout).trim(),
+            contents.len().to_string()
+        );
+
+        let output = remote.exec("echo out; echo err >&2").unwrap();
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"err\n");
+
+        let err = remote.exec("echo failed >&2; exit 3").unwrap_err();
+        let err = err.downcast::<ExitError>().unwrap();
+        assert_eq!(err.exit_status, 3);
+        assert_eq!(err.stderr, "failed\n");
+
+        let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+        let err = remote
+            .exec_streamed("echo streamed; exit 4", &mut stdout, &mut stderr)
+            .unwrap_err();
+        assert_eq!(err.downcast::<ExitError>().unwrap().exit_status, 4);
+        assert_eq!(stdout, b"streamed\n");
+
+        remote.exec(&format!("rm {}", uploaded)).unwrap();
+        fs::remove_file(local).unwrap();
+    }
+}
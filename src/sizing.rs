@@ -0,0 +1,275 @@
+use crate::eif::{Eif, SectionKind};
+use crate::instance_types::InstanceType;
+use crate::remote::RemoteHost;
+use failure::Error;
+use serde::Serialize;
+use std::fmt;
+
+/// Memory added on top of what the image needs, for the application itself.
+pub const DEFAULT_HEADROOM_MIB: u64 = 256;
+/// Memory left to the parent instance's OS, allocator and socat proxy.
+pub const HOST_RESERVED_MIB: u64 = 1024;
+/// The kernel and ramdisks are copied into enclave memory and the ramdisks are
+/// then unpacked into the in-memory root filesystem. `nitro-cli` refuses to
+/// start an enclave with less than 4x the size of the EIF file.
+const IMAGE_FACTOR: u64 = 4;
+const MIB: u64 = 1024 * 1024;
+
+/// How much memory an enclave gets and why.
+#[derive(Clone, Debug, Serialize)]
+pub struct MemorySizing {
+    /// Length of the EIF file, which the minimum is derived from.
+    pub eif_bytes: u64,
+    pub kernel_bytes: u64,
+    pub ramdisk_bytes: u64,
+    /// Smallest allocation the image can boot in.
+    pub minimum_mib: u64,
+    pub headroom_mib: u64,
+    /// Explicitly requested with `--memory`, overriding minimum plus headroom.
+    pub requested_mib: Option<u64>,
+    pub memory_mib: u64,
+    pub instance_memory_mib: Option<u64>,
+    pub available_mib: Option<u64>,
+}
+
+impl MemorySizing {
+    pub fn for_eif(
+        eif: &Eif,
+        headroom_mib: u64,
+        requested_mib: Option<u64>,
+    ) -> Result<Self, Error> {
+        let kernel_bytes =
+            eif.section_size(SectionKind::Kernel) + eif.section_size(SectionKind::Cmdline);
+        let ramdisk_bytes = eif.section_size(SectionKind::Ramdisk);
+        let minimum_mib = (eif.file_size * IMAGE_FACTOR).div_ceil(MIB);
+
+        let sizing = MemorySizing {
+            eif_bytes: eif.file_size,
+            kernel_bytes,
+            ramdisk_bytes,
+            minimum_mib,
+            headroom_mib,
+            requested_mib,
+            memory_mib: requested_mib.unwrap_or(minimum_mib + headroom_mib),
+            instance_memory_mib: None,
+            available_mib: None,
+        };
+        if sizing.memory_mib < minimum_mib {
+            return Err(failure::err_msg(format!(
+                "{} MiB of enclave memory is too little for this EIF: {}",
+                sizing.memory_mib, sizing
+            )));
+        }
+        Ok(sizing)
+    }
+
+    /// Check the enclave fits the catalogued size of `instance_type`, before
+    /// anything is changed on the instance.
+    pub fn check_instance_type(
+        &self,
+        instance_type: &InstanceType,
+        cpu_count: u64,
+    ) -> Result<(), Error> {
+        instance_type.check_enclave_cpus(cpu_count)?;
+        if self.memory_mib <= instance_type.max_enclave_memory_mib() {
+            return Ok(());
+        }
+        let suggestion =
+            match InstanceType::smallest_for(instance_type.arch, cpu_count, self.memory_mib) {
+                Some(fits) => format!(" The smallest instance type that fits is {}.", fits.name),
+                None => String::new(),
+            };
+        Err(failure::err_msg(format!(
+            "{} MiB of enclave memory does not fit {}, which can give enclaves {} MiB.{}",
+            self.memory_mib,
+            instance_type.name,
+            instance_type.max_enclave_memory_mib(),
+            suggestion
+        )))
+    }
+
+    /// Check the enclave fits in the instance next to the host's reservation.
+    pub fn check_instance(&mut self, instance_memory_mib: u64) -> Result<(), Error> {
+        let available_mib = instance_memory_mib.saturating_sub(HOST_RESERVED_MIB);
+        self.instance_memory_mib = Some(instance_memory_mib);
+        self.available_mib = Some(available_mib);
+        if self.memory_mib > available_mib {
+            return Err(failure::err_msg(format!(
+                "{} MiB of enclave memory does not fit the instance: {}",
+                self.memory_mib, self
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for MemorySizing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the {} KiB EIF (kernel {} KiB, ramdisks {} KiB) needs at least {} MiB ({}x its size)",
+            self.eif_bytes / 1024,
+            self.kernel_bytes / 1024,
+            self.ramdisk_bytes / 1024,
+            self.minimum_mib,
+            IMAGE_FACTOR
+        )?;
+        match self.requested_mib {
+            Some(requested) => write!(f, ", {} MiB requested", requested)?,
+            None => write!(
+                f,
+                ", plus {} MiB headroom is {} MiB",
+                self.headroom_mib, self.memory_mib
+            )?,
+        }
+        if let (Some(total), Some(available)) = (self.instance_memory_mib, self.available_mib) {
+            write!(
+                f,
+                "; the instance has {} MiB of which {} MiB can go to enclaves after reserving {} MiB for the host",
+                total, available, HOST_RESERVED_MIB
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Total memory of the instance behind `remote`, in MiB.
+pub fn instance_memory_mib(remote: &RemoteHost) -> Result<u64, Error> {
+    let out = remote.exec("grep MemTotal /proc/meminfo")?;
+    let meminfo = String::from_utf8_lossy(&out.stdout);
+    // MemTotal:       16106152 kB
+    match meminfo
+        .split_whitespace()
+        .nth(1)
+        .and_then(|kib| kib.parse::<u64>().ok())
+    {
+        Some(kib) => Ok(kib / 1024),
+        None => Err(failure::err_msg(format!(
+            "Unable to read the instance memory from '{}'.",
+            meminfo.trim()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::Arch;
+    use crate::eif::Section;
+
+    fn eif(file_size: u64) -> Eif {
+        let section = |kind, size| Section {
+            kind,
+            offset: 0,
+            size,
+        };
+        Eif {
+            version: 4,
+            arch: Arch::X86_64,
+            default_memory: 0,
+            default_cpus: 2,
+            file_size,
+            sections: vec![
+                section(SectionKind::Kernel, 8 * MIB),
+                section(SectionKind::Cmdline, 100),
+                section(SectionKind::Ramdisk, 4 * MIB),
+                section(SectionKind::Ramdisk, 100 * MIB),
+                section(SectionKind::Signature, MIB),
+            ],
+            cmdline: None,
+            metadata: None,
+            signed: true,
+            crc32: String::new(),
+            computed_crc32: String::new(),
+            measurements: Default::default(),
+        }
+    }
+
+    #[test]
+    fn minimum_is_four_times_the_whole_file() {
+        // Sections add up to 113 MiB, the signature and headers make the file 114 MiB
+        let sizing = MemorySizing::for_eif(&eif(114 * MIB), 0, None).unwrap();
+        assert_eq!(sizing.eif_bytes, 114 * MIB);
+        assert_eq!(sizing.kernel_bytes, 8 * MIB + 100);
+        assert_eq!(sizing.ramdisk_bytes, 104 * MIB);
+        assert_eq!(sizing.minimum_mib, 456);
+        assert_eq!(sizing.memory_mib, 456);
+    }
+
+    #[test]
+    fn minimum_rounds_up_to_whole_mib() {
+        assert_eq!(
+            MemorySizing::for_eif(&eif(MIB), 0, None)
+                .unwrap()
+                .minimum_mib,
+            4
+        );
+        assert_eq!(
+            MemorySizing::for_eif(&eif(MIB + 1), 0, None)
+                .unwrap()
+                .minimum_mib,
+            5
+        );
+        assert_eq!(
+            MemorySizing::for_eif(&eif(MIB / 4), 0, None)
+                .unwrap()
+                .minimum_mib,
+            1
+        );
+    }
+
+    #[test]
+    fn headroom_is_added_unless_memory_is_requested() {
+        let sizing = MemorySizing::for_eif(&eif(100 * MIB), DEFAULT_HEADROOM_MIB, None).unwrap();
+        assert_eq!(sizing.memory_mib, 400 + DEFAULT_HEADROOM_MIB);
+
+        let sizing =
+            MemorySizing::for_eif(&eif(100 * MIB), DEFAULT_HEADROOM_MIB, Some(400)).unwrap();
+        assert_eq!(sizing.memory_mib, 400);
+        assert_eq!(sizing.requested_mib, Some(400));
+
+        let err =
+            MemorySizing::for_eif(&eif(100 * MIB), DEFAULT_HEADROOM_MIB, Some(399)).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("399 MiB of enclave memory is too little"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn the_host_keeps_its_reservation() {
+        let mut sizing = MemorySizing::for_eif(&eif(MIB), 0, Some(3072)).unwrap();
+        sizing.check_instance(4096).unwrap();
+        assert_eq!(sizing.available_mib, Some(3072));
+
+        let mut sizing = MemorySizing::for_eif(&eif(MIB), 0, Some(3073)).unwrap();
+        let err = sizing.check_instance(4096).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("after reserving 1024 MiB for the host"),
+            "{}",
+            err
+        );
+        assert!(sizing.check_instance(512).is_err());
+        assert_eq!(sizing.available_mib, Some(0));
+    }
+
+    #[test]
+    fn suggests_an_instance_type_that_fits() {
+        let c5 = InstanceType::lookup("c5.xlarge").unwrap();
+        let sizing = MemorySizing::for_eif(&eif(MIB), 0, Some(7168)).unwrap();
+        sizing.check_instance_type(&c5, 2).unwrap();
+
+        let sizing = MemorySizing::for_eif(&eif(MIB), 0, Some(7169)).unwrap();
+        let err = sizing.check_instance_type(&c5, 2).unwrap_err().to_string();
+        assert!(err.contains("can give enclaves 7168 MiB"), "{}", err);
+        assert!(
+            err.contains("The smallest instance type that fits is"),
+            "{}",
+            err
+        );
+        assert!(sizing.check_instance_type(&c5, 3).is_err());
+    }
+}
@@ -0,0 +1,421 @@
+use crate::cf_utilities::StackOutputs;
+use crate::egress::Destination;
+use crate::ingress::{self, Source};
+use crate::output::{SetupOutput, UpdateOutput};
+use crate::ports::{PortMapping, DEFAULT_VSOCK_PORT};
+use failure::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha384};
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+#[cfg(test)]
+thread_local! {
+    /// Replaces `~/.nitrogen` for the tests running on this thread.
+    static TEST_DIR: std::cell::RefCell<Option<PathBuf>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Root of the files nitrogen manages on the local machine.
+pub fn nitrogen_dir() -> PathBuf {
+    #[cfg(test)]
+    if let Some(dir) = TEST_DIR.with(|dir| dir.borrow().clone()) {
+        return dir;
+    }
+    home::home_dir().unwrap_or_default().join(".nitrogen")
+}
+
+/// Point [`nitrogen_dir`] at a fresh temporary directory for the calling test.
+#[cfg(test)]
+pub(crate) fn use_temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("nitrogen-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    TEST_DIR.with(|test_dir| *test_dir.borrow_mut() = Some(dir.clone()));
+    dir
+}
+
+/// Check `name` is a valid CloudFormation stack name, which also keeps the
+/// files named after it inside nitrogen's directory.
+pub fn check_stack_name(name: &str) -> Result<(), Error> {
+    let mut chars = name.chars();
+    let valid = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '-')
+        && name.len() <= 128;
+    if !valid {
+        return Err(failure::err_msg(format!(
+            "'{}' is not a valid stack name, which must start with a letter, contain only \
+            letters, digits and hyphens, and be at most 128 characters long.",
+            name
+        )));
+    }
+    Ok(())
+}
+
+pub fn stacks_dir() -> PathBuf {
+    nitrogen_dir().join("stacks")
+}
+
+/// Parameters and hash of the EIF last deployed to a stack.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Deployment {
+    pub eif: String,
+    pub eif_sha384: String,
+    pub cpu_count: u64,
+    pub memory_mib: u64,
+    pub debug_mode: bool,
+    pub enclave_id: String,
+    /// Destinations the enclave may connect out to, empty for deployments
+    /// predating egress.
+    #[serde(default)]
+    pub egress: Vec<Destination>,
+}
+
+/// Everything nitrogen knows locally about a stack it created, stored in
+/// `~/.nitrogen/stacks/<name>.json`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StackState {
+    pub name: String,
+    pub region: Option<String>,
+    pub outputs: StackOutputs,
+    pub instance_type: String,
+    /// Every forwarded port. The first is the `Port` parameter of the stack.
+    #[serde(default)]
+    pub ports: Vec<PortMapping>,
+    /// Sources allowed to reach the ports, empty for stacks predating them.
+    #[serde(default)]
+    pub service_locations: Vec<Source>,
+    pub ssh_location: String,
+    /// Absolute path of the public key the instance key pair was created from.
+    pub public_key: String,
+    /// Absolute path of the private key last used to reach the instance.
+    pub private_key: Option<String>,
+    pub deployment: Option<Deployment>,
+}
+
+impl StackState {
+    pub fn new(
+        setup: &SetupOutput,
+        region: Option<String>,
+        instance_type: &str,
+        ports: &[PortMapping],
+        service_locations: &[Source],
+        ssh_location: &str,
+        public_key: &str,
+    ) -> Self {
+        StackState {
+            name: setup.name.clone(),
+            region,
+            outputs: setup.outputs.clone(),
+            instance_type: instance_type.to_string(),
+            ports: ports.to_vec(),
+            service_locations: service_locations.to_vec(),
+            ssh_location: ssh_location.to_string(),
+            public_key: absolute(public_key),
+            private_key: None,
+            deployment: None,
+        }
+    }
+
+    pub fn path(name: &str) -> Result<PathBuf, Error> {
+        check_stack_name(name)?;
+        Ok(stacks_dir().join(format!("{}.json", name)))
+    }
+
+    pub fn load(name: &str) -> Result<Option<Self>, Error> {
+        let path = Self::path(name)?;
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        match serde_json::from_str(&contents).and_then(|mut state: Value| {
+            // Stacks from before port mappings recorded a single `port`
+            if state.get("ports").is_none() {
+                if let Some(port) = state.get("port").and_then(Value::as_u64) {
+                    state["ports"] = json!([{ "host": port, "vsock": DEFAULT_VSOCK_PORT }]);
+                }
+            }
+            serde_json::from_value(state)
+        }) {
+            Ok(state) => Ok(Some(state)),
+            Err(err) => Err(failure::err_msg(format!(
+                "Unable to parse {}: {}",
+                path.display(),
+                err
+            ))),
+        }
+    }
+
+    /// All registered stacks, sorted by name.
+    pub fn list() -> Result<Vec<Self>, Error> {
+        let entries = match fs::read_dir(stacks_dir()) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+        let mut stacks = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                // Not written by nitrogen
+                if check_stack_name(name).is_err() {
+                    continue;
+                }
+                if let Some(state) = Self::load(name)? {
+                    stacks.push(state);
+                }
+            }
+        }
+        stacks.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(stacks)
+    }
+
+    pub fn save(&self) -> Result<PathBuf, Error> {
+        let path = Self::path(&self.name)?;
+        fs::create_dir_all(stacks_dir())?;
+        fs::write(&path, serde_json::to_string_pretty(self)?)?;
+        debug!(name = self.name, path = %path.display(), "Saved stack state.");
+        Ok(path)
+    }
+
+    pub fn remove(name: &str) -> Result<(), Error> {
+        match fs::remove_file(Self::path(name)?) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn set_private_key(&mut self, private_key: &str) {
+        self.private_key = Some(absolute(private_key));
+    }
+
+    /// Record the parameters an executed update changed.
+    pub fn record_update(
+        &mut self,
+        update: &UpdateOutput,
+        instance_type: Option<String>,
+        port: Option<u16>,
+        ssh_location: Option<String>,
+        service_locations: Vec<Source>,
+    ) {
+        // A replaced or restarted instance no longer runs the enclave
+        if update
+            .changes
+            .iter()
+            .any(|change| change.resource_type == "AWS::EC2::Instance")
+        {
+            self.deployment = None;
+        }
+        self.outputs = update.outputs.clone();
+        if let Some(instance_type) = instance_type {
+            self.instance_type = instance_type;
+        }
+        if let (Some(port), Some(primary)) = (port, self.ports.first_mut()) {
+            primary.host = port;
+        }
+        if let Some(ssh_location) = ssh_location {
+            self.ssh_location = ssh_location;
+        }
+        if !service_locations.is_empty() {
+            self.service_locations = service_locations;
+        }
+    }
+
+    pub fn record_deployment(
+        &mut self,
+        eif: &str,
+        cpu_count: u64,
+        memory_mib: u64,
+        debug_mode: bool,
+        enclave_id: &str,
+        egress: &[Destination],
+    ) -> Result<(), Error> {
+        self.deployment = Some(Deployment {
+            eif: absolute(eif),
+            eif_sha384: sha384_file(Path::new(eif))?,
+            cpu_count,
+            memory_mib,
+            debug_mode,
+            enclave_id: enclave_id.to_string(),
+            egress: egress.to_vec(),
+        });
+        Ok(())
+    }
+}
+
+impl fmt::Display for StackState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "name:           {}", self.name)?;
+        writeln!(
+            f,
+            "region:         {}",
+            self.region.as_deref().unwrap_or("-")
+        )?;
+        writeln!(f, "stack_id:       {}", self.outputs.stack_id)?;
+        writeln!(f, "instance_id:    {}", self.outputs.instance_id)?;
+        writeln!(f, "instance_type:  {}", self.instance_type)?;
+        let address = |address: &Option<String>| address.clone().unwrap_or("-".to_string());
+        writeln!(f, "public_dns:     {}", address(&self.outputs.public_dns))?;
+        writeln!(f, "public_ip:      {}", address(&self.outputs.public_ip))?;
+        writeln!(f, "private_ip:     {}", address(&self.outputs.private_ip))?;
+        writeln!(
+            f,
+            "ports:          {}",
+            self.ports
+                .iter()
+                .map(PortMapping::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )?;
+        if !self.service_locations.is_empty() {
+            writeln!(
+                f,
+                "service_locations: {}",
+                ingress::service_locations(&self.service_locations)
+            )?;
+        }
+        writeln!(f, "ssh_location:   {}", self.ssh_location)?;
+        writeln!(f, "public_key:     {}", self.public_key)?;
+        write!(
+            f,
+            "private_key:    {}",
+            self.private_key.as_deref().unwrap_or("-")
+        )?;
+        if let Some(deployment) = &self.deployment {
+            writeln!(f)?;
+            writeln!(f, "eif:            {}", deployment.eif)?;
+            writeln!(f, "eif_sha384:     {}", deployment.eif_sha384)?;
+            writeln!(f, "enclave_id:     {}", deployment.enclave_id)?;
+            if !deployment.egress.is_empty() {
+                writeln!(
+                    f,
+                    "egress:         {}",
+                    deployment
+                        .egress
+                        .iter()
+                        .map(Destination::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )?;
+            }
+            write!(
+                f,
+                "enclave:        {} CPUs, {} MiB{}",
+                deployment.cpu_count,
+                deployment.memory_mib,
+                if deployment.debug_mode {
+                    ", debug mode"
+                } else {
+                    ""
+                }
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Hex encoded SHA-384 digest of a file.
+pub fn sha384_file(path: &Path) -> Result<String, Error> {
+    let mut hasher = Sha384::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Paths are stored absolute so the state is usable from any directory.
+pub(crate) fn absolute(path: &str) -> String {
+    match fs::canonicalize(path) {
+        Ok(path) => path.display().to_string(),
+        Err(_) => path.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_cloudformation_stack_names() {
+        for name in ["a", "my-stack", "Stack2", &format!("s{}", "0".repeat(127))] {
+            check_stack_name(name).unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_names_that_could_escape_the_state_directory() {
+        for name in [
+            "",
+            "../x",
+            "a/b",
+            "/etc/passwd",
+            "..",
+            "2stack",
+            "-stack",
+            "my_stack",
+            "stack.json",
+            &format!("s{}", "0".repeat(128)),
+        ] {
+            assert!(check_stack_name(name).is_err(), "{}", name);
+            assert!(StackState::path(name).is_err(), "{}", name);
+            assert!(StackState::load(name).is_err(), "{}", name);
+            assert!(StackState::remove(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn loads_state_from_before_port_mappings() {
+        let dir = use_temp_dir("legacy-state");
+        fs::create_dir_all(stacks_dir()).unwrap();
+        fs::write(
+            stacks_dir().join("legacy.json"),
+            r#"{
+                "name": "legacy",
+                "region": "us-east-1",
+                "outputs": {
+                    "stack_id": "arn:aws:cloudformation:us-east-1:123456789012:stack/legacy/1",
+                    "instance_id": "i-0123456789abcdef0",
+                    "public_ip": "203.0.113.1",
+                    "availability_zone": "us-east-1a",
+                    "public_dns": "ec2-203-0-113-1.compute-1.amazonaws.com"
+                },
+                "instance_type": "c5.xlarge",
+                "port": 5000,
+                "ssh_location": "0.0.0.0/0",
+                "public_key": "/home/user/.ssh/id_ed25519.pub",
+                "deployment": {
+                    "eif": "/home/user/app.eif",
+                    "eif_sha384": "00",
+                    "cpu_count": 2,
+                    "memory_mib": 2048,
+                    "debug_mode": false,
+                    "enclave_id": "i-0123456789abcdef0-enc0123456789abcdef"
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let state = StackState::load("legacy").unwrap().unwrap();
+        assert_eq!(
+            state.ports,
+            [PortMapping {
+                host: 5000,
+                vsock: DEFAULT_VSOCK_PORT
+            }]
+        );
+        assert!(state.service_locations.is_empty());
+        assert_eq!(state.private_key, None);
+        assert_eq!(state.outputs.private_ip, None);
+        assert_eq!(state.outputs.host_key, None);
+        assert!(state.deployment.unwrap().egress.is_empty());
+        assert_eq!(StackState::list().unwrap().len(), 1);
+        assert!(StackState::load("missing").unwrap().is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -22,10 +22,32 @@ pub const SETUP_TEMPLATE: &str = r##"{
         "MaxValue": 65536
     },
 
+    "VsockPort": {
+        "Description": "Vsock port of the enclave that Port is forwarded to",
+        "Type": "Number",
+        "Default": 5000,
+        "MinValue": 1,
+        "MaxValue": 4294967295
+    },
+
+    "ServiceLocations": {
+        "Description": "Sources allowed to reach the forwarded ports: IPv4 or IPv6 CIDR ranges and security group ids, comma separated. Nitrogen generates the ingress rules from it",
+        "Type": "String",
+        "Default": "0.0.0.0/0"
+    },
+
+    "ExtraPortMappings": {
+        "Description": "Further host:vsock port mappings to forward, comma separated",
+        "Type": "String",
+        "Default": "",
+        "AllowedPattern": "^$|^\\d+:\\d+(,\\d+:\\d+)*$",
+        "ConstraintDescription": "must be a comma separated list of host:vsock port pairs"
+    },
+
     "InstanceType" : {
       "Description" : "Type of the ec2 instance",
       "Type" : "String",
-      "ConstraintDescription" : "Must be a virtualized Nitro-based instance type with at least two vCPUs, except t3, t3a, t4g, a1, c6g, c6gd, m6g, m6gd, r6g, and r6gd."
+      "ConstraintDescription" : "Must be a Nitro Enclaves capable instance type: at least four vCPUs for Intel and AMD, or two for Graviton, except t3, t3a, t4g and a1."
     },
 
     "DiskSize" : {
@@ -46,11 +68,38 @@ pub const SETUP_TEMPLATE: &str = r##"{
    },
 
    "LatestAmiId": {
+    "Description" : "SSM parameter of the Amazon Linux 2 AMI, matching the architecture of the instance type",
     "Type": "AWS::SSM::Parameter::Value<AWS::EC2::Image::Id>",
     "Default": "/aws/service/ami-amazon-linux-latest/amzn2-ami-hvm-x86_64-gp2"
+   },
+
+   "VpcId": {
+    "Description" : "VPC to create the security group in, empty for the default VPC",
+    "Type": "String",
+    "Default": ""
+   },
+
+   "SubnetId": {
+    "Description" : "Subnet to launch the instance in, empty for a default subnet of the default VPC",
+    "Type": "String",
+    "Default": ""
+   },
+
+   "AssociatePublicIp": {
+    "Description" : "Whether the instance gets a public IP address, only honoured with a SubnetId",
+    "Type": "String",
+    "Default": "true",
+    "AllowedValues": ["true", "false"]
    }
   },
 
+  "Conditions" : {
+    "HasVpc" : { "Fn::Not" : [ { "Fn::Equals" : [ { "Ref" : "VpcId" }, "" ] } ] },
+    "HasSubnet" : { "Fn::Not" : [ { "Fn::Equals" : [ { "Ref" : "SubnetId" }, "" ] } ] },
+    "IsPublic" : { "Fn::Equals" : [ { "Ref" : "AssociatePublicIp" }, "true" ] },
+    "IsUsEast1" : { "Fn::Equals" : [ { "Ref" : "AWS::Region" }, "us-east-1" ] }
+  },
+
   "Resources" : {
       "ImportedKeyPair": {
         "Type": "AWS::EC2::KeyPair",
@@ -62,6 +111,12 @@ pub const SETUP_TEMPLATE: &str = r##"{
 
     "EC2Instance" : {
       "Type" : "AWS::EC2::Instance",
+      "CreationPolicy" : {
+        "ResourceSignal" : {
+          "Count" : "1",
+          "Timeout" : "PT15M"
+        }
+      },
       "Metadata": {
         "AWS::CloudFormation::Init": {
             "configSets": {
@@ -76,6 +131,47 @@ pub const SETUP_TEMPLATE: &str = r##"{
                       "aws-nitro-enclaves-cli": []
                     }
                 },
+                "files": {
+                    "/usr/local/bin/nitrogen-forward": {
+                        "content": { "Fn::Join": [ "\n", [
+                            "#!/bin/bash -e",
+                            "# Forward every host:vsock mapping from the current user data, so changes to",
+                            "# the mappings apply once the instance restarts",
+                            "token=$(curl -sf -X PUT -H 'X-aws-ec2-metadata-token-ttl-seconds: 60' http://169.254.169.254/latest/api/token)",
+                            "mappings=$(curl -sf -H \"X-aws-ec2-metadata-token: $token\" http://169.254.169.254/latest/user-data | sed -n 's/^# nitrogen-ports: //p')",
+                            "docker rm -f $(docker ps -aq --filter name=socat) 2>/dev/null || true",
+                            "for mapping in $(echo \"$mappings\" | tr ',' ' '); do",
+                            "  host=${mapping%%:*}; vsock=${mapping##*:}",
+                            "  docker run -d --restart unless-stopped -p $host:$host --name socat-$host alpine/socat tcp-listen:$host,fork,keepalive,reuseaddr vsock-connect:16:$vsock,keepalive",
+                            "done",
+                            ""
+                        ] ] },
+                        "mode": "000755",
+                        "owner": "root",
+                        "group": "root"
+                    },
+                    "/etc/systemd/system/nitrogen-forward.service": {
+                        "content": { "Fn::Join": [ "\n", [
+                            "[Unit]",
+                            "Description=Forward service ports to the enclave",
+                            "Wants=network-online.target",
+                            "After=network-online.target docker.service",
+                            "Requires=docker.service",
+                            "",
+                            "[Service]",
+                            "Type=oneshot",
+                            "RemainAfterExit=yes",
+                            "ExecStart=/usr/local/bin/nitrogen-forward",
+                            "",
+                            "[Install]",
+                            "WantedBy=multi-user.target",
+                            ""
+                        ] ] },
+                        "mode": "000644",
+                        "owner": "root",
+                        "group": "root"
+                    }
+                },
                 "services": {
                     "sysvinit": {
                         "docker": {
@@ -97,7 +193,19 @@ pub const SETUP_TEMPLATE: &str = r##"{
       },
       "Properties" : {
         "InstanceType" : { "Ref" : "InstanceType" },
-        "SecurityGroups" : [ { "Ref" : "InstanceSecurityGroup" } ],
+        "SecurityGroupIds" : { "Fn::If" : [ "HasSubnet",
+          { "Ref" : "AWS::NoValue" },
+          [ { "Fn::GetAtt" : [ "InstanceSecurityGroup", "GroupId" ] } ]
+        ] },
+        "NetworkInterfaces" : { "Fn::If" : [ "HasSubnet",
+          [ {
+            "DeviceIndex" : "0",
+            "SubnetId" : { "Ref" : "SubnetId" },
+            "GroupSet" : [ { "Fn::GetAtt" : [ "InstanceSecurityGroup", "GroupId" ] } ],
+            "AssociatePublicIpAddress" : { "Ref" : "AssociatePublicIp" }
+          } ],
+          { "Ref" : "AWS::NoValue" }
+        ] },
         "KeyName" : { "Ref" : "ImportedKeyPair" },
         "ImageId" : { "Ref" : "LatestAmiId" },
         "EnclaveOptions": {
@@ -122,6 +230,12 @@ pub const SETUP_TEMPLATE: &str = r##"{
               [
                 "#!/bin/bash -xe\n",
                 "yum install -y aws-cfn-bootstrap\n",
+                "# Fail the stack right away instead of at the CreationPolicy timeout\n",
+                "trap '/opt/aws/bin/cfn-signal -e 1 --stack ",
+                { "Ref":"AWS::StackName" },
+                " --resource EC2Instance --region ",
+                { "Ref":"AWS::Region" },
+                "' ERR\n",
                 "amazon-linux-extras enable aws-nitro-enclaves-cli\n",
                 "# Install the files and packages from the metadata\n",
                 "/opt/aws/bin/cfn-init -v ",
@@ -135,13 +249,29 @@ pub const SETUP_TEMPLATE: &str = r##"{
                 "systemctl start nitro-enclaves-allocator.service && systemctl enable nitro-enclaves-allocator.service\n",
                 "systemctl start docker && systemctl enable docker\n",
                 "docker pull alpine/socat:latest\n",
-                "docker run -d -p",
+                "# Read back by nitrogen-forward on every boot\n",
+                "# nitrogen-ports: ",
                 { "Ref" : "Port" },
                 ":",
-                { "Ref" : "Port" },
-                " --name socat alpine/socat tcp-listen:",
-                { "Ref" : "Port" },
-                ",fork,keepalive,reuseaddr vsock-connect:16:5000,keepalive\n"
+                { "Ref" : "VsockPort" },
+                ",",
+                { "Ref" : "ExtraPortMappings" },
+                "\n",
+                "systemctl daemon-reload\n",
+                "systemctl start nitrogen-forward.service && systemctl enable nitrogen-forward.service\n",
+                "# Publish the SSH host key so nitrogen can pin it\n",
+                "/opt/aws/bin/cfn-signal -e 0 --id HostKey",
+                "         --data \"$(cut -d ' ' -f 1,2 /etc/ssh/ssh_host_ed25519_key.pub)\" '",
+                { "Ref" : "HostKeyWaitHandle" },
+                "'\n",
+                "# The host is ready once the allocator and every socat proxy are up\n",
+                "systemctl is-active --quiet nitro-enclaves-allocator.service\n",
+                "[ -z \"$(docker ps -a --filter name=socat --format '{{.State}}' | grep -v running)\" ]\n",
+                "/opt/aws/bin/cfn-signal -e 0 --stack ",
+                { "Ref":"AWS::StackName" },
+                " --resource EC2Instance --region ",
+                { "Ref":"AWS::Region" },
+                "\n"
               ]
             ]
           }
@@ -149,22 +279,51 @@ pub const SETUP_TEMPLATE: &str = r##"{
       }
     },
 
+    "PublicAddress" : {
+      "Type" : "AWS::EC2::EIP",
+      "Condition" : "IsPublic",
+      "Properties" : {
+        "Domain" : "vpc",
+        "Tags" : [
+            {"Key" : "Name", "Value" : { "Ref": "InstanceName"}}
+        ]
+      }
+    },
+
+    "PublicAddressAssociation" : {
+      "Type" : "AWS::EC2::EIPAssociation",
+      "Condition" : "IsPublic",
+      "Properties" : {
+        "AllocationId" : { "Fn::GetAtt" : [ "PublicAddress", "AllocationId" ] },
+        "InstanceId" : { "Ref" : "EC2Instance" }
+      }
+    },
+
+    "HostKeyWaitHandle" : {
+      "Type" : "AWS::CloudFormation::WaitConditionHandle"
+    },
+
+    "HostKeyWaitCondition" : {
+      "Type" : "AWS::CloudFormation::WaitCondition",
+      "DependsOn" : "EC2Instance",
+      "Properties" : {
+        "Handle" : { "Ref" : "HostKeyWaitHandle" },
+        "Timeout" : "900",
+        "Count" : "1"
+      }
+    },
+
     "InstanceSecurityGroup" : {
       "Type" : "AWS::EC2::SecurityGroup",
       "Properties" : {
         "GroupDescription" : "Enable SSH access via port 22",
+        "VpcId" : { "Fn::If" : [ "HasVpc", { "Ref" : "VpcId" }, { "Ref" : "AWS::NoValue" } ] },
         "SecurityGroupIngress" : [
           {
             "IpProtocol" : "tcp",
             "FromPort" : "22",
             "ToPort" : "22",
             "CidrIp" : { "Ref" : "SSHLocation"}
-          },
-          {
-            "IpProtocol" : "tcp",
-            "FromPort" : { "Ref" : "Port" },
-            "ToPort" : { "Ref" : "Port" },
-            "CidrIp" : "0.0.0.0/0"
           }
         ]
       }
@@ -181,12 +340,33 @@ pub const SETUP_TEMPLATE: &str = r##"{
       "Value" : { "Fn::GetAtt" : [ "EC2Instance", "AvailabilityZone" ] }
     },
     "PublicDNS" : {
-      "Description" : "Public DNSName of the newly created EC2 instance",
-      "Value" : { "Fn::GetAtt" : [ "EC2Instance", "PublicDnsName" ] }
+      "Description" : "Public DNSName of the Elastic IP address of the newly created EC2 instance",
+      "Condition" : "IsPublic",
+      "Value" : { "Fn::Join" : [ "", [
+        "ec2-",
+        { "Fn::Join" : [ "-", { "Fn::Split" : [ ".", { "Ref" : "PublicAddress" } ] } ] },
+        { "Fn::If" : [ "IsUsEast1",
+          ".compute-1.amazonaws.com",
+          { "Fn::Join" : [ "", [ ".", { "Ref" : "AWS::Region" }, ".compute.amazonaws.com" ] ] }
+        ] }
+      ] ] }
     },
     "PublicIP" : {
-      "Description" : "Public IP address of the newly created EC2 instance",
-      "Value" : { "Fn::GetAtt" : [ "EC2Instance", "PublicIp" ] }
+      "Description" : "Elastic IP address of the newly created EC2 instance, kept across stops",
+      "Condition" : "IsPublic",
+      "Value" : { "Ref" : "PublicAddress" }
+    },
+    "PrivateDNS" : {
+      "Description" : "Private DNSName of the newly created EC2 instance",
+      "Value" : { "Fn::GetAtt" : [ "EC2Instance", "PrivateDnsName" ] }
+    },
+    "PrivateIP" : {
+      "Description" : "Private IP address of the newly created EC2 instance",
+      "Value" : { "Fn::GetAtt" : [ "EC2Instance", "PrivateIp" ] }
+    },
+    "HostKey" : {
+      "Description" : "SSH host key of the newly created EC2 instance, keyed by signal id",
+      "Value" : { "Fn::GetAtt" : [ "HostKeyWaitCondition", "Data" ] }
     }
   }
 }